@@ -0,0 +1,406 @@
+use crate::check_pool::Deadline;
+use base64::{engine::general_purpose, Engine as _};
+use std::fmt;
+
+const CRC24_INIT: u32 = 0xB704CE;
+const CRC24_POLY: u32 = 0x1864CFB;
+
+/// Incremental CRC24 checksum of RFC 9580 section 6.1.
+pub struct Crc24(u32);
+
+impl Crc24 {
+    pub fn new() -> Self {
+        Crc24(CRC24_INIT)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.0 ^= (b as u32) << 16;
+            for _ in 0..8 {
+                self.0 <<= 1;
+                if self.0 & 0x1000000 != 0 {
+                    self.0 ^= CRC24_POLY;
+                }
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        self.0 & 0xFFFFFF
+    }
+}
+
+/// Computes the CRC24 checksum of `data`.
+pub fn crc24(data: &[u8]) -> u32 {
+    let mut crc = Crc24::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// Settings for parsing ASCII armor.
+#[derive(Debug, Clone)]
+pub struct ArmorPolicy {
+    /// Accept armor without a CRC24 checksum line, as permitted by RFC 9580.
+    pub allow_missing_checksum: bool,
+    /// Armor header keys accepted on incoming mail.
+    pub headers_incoming: Vec<String>,
+    /// Armor header keys accepted on outgoing mail.
+    pub headers_outgoing: Vec<String>,
+    pub max_headers: usize,
+    /// Maximum length of a header line, excluding the line ending.
+    pub max_header_length: usize,
+}
+
+impl Default for ArmorPolicy {
+    fn default() -> Self {
+        Self {
+            allow_missing_checksum: true,
+            headers_incoming: ["Version", "Comment", "Charset", "Hash", "MessageID"]
+                .map(String::from)
+                .to_vec(),
+            headers_outgoing: Vec::new(),
+            max_headers: 16,
+            max_header_length: 1024,
+        }
+    }
+}
+
+impl ArmorPolicy {
+    pub fn allowed_headers(&self, outgoing: bool) -> &[String] {
+        if outgoing {
+            &self.headers_outgoing
+        } else {
+            &self.headers_incoming
+        }
+    }
+}
+
+/// An armored block split into its parts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Armor<'a> {
+    /// Armor headers in order of appearance.
+    pub headers: Vec<(&'a str, &'a str)>,
+    /// Radix-64 data including the optional checksum line.
+    pub body: &'a str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ArmorError {
+    MissingBegin(String),
+    MissingEnd(String),
+    MalformedHeader,
+    HeaderNotAllowed(String),
+    TooManyHeaders,
+    HeaderTooLong,
+    /// The armored data is not valid Radix-64.
+    Malformed(String),
+    /// The checksum line is not a valid encoded CRC24.
+    MalformedChecksum,
+    MissingChecksum,
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// The check ran out of time while decoding.
+    Timeout,
+}
+
+impl fmt::Display for ArmorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArmorError::MissingBegin(label) => write!(f, "Missing BEGIN {} prefix", label),
+            ArmorError::MissingEnd(label) => write!(f, "Missing END {} suffix", label),
+            ArmorError::MalformedHeader => write!(f, "Malformed armor header line"),
+            ArmorError::HeaderNotAllowed(key) => write!(f, "Armor header '{}' not allowed", key),
+            ArmorError::TooManyHeaders => write!(f, "Too many armor headers"),
+            ArmorError::HeaderTooLong => write!(f, "Armor header line too long"),
+            ArmorError::Malformed(e) => write!(f, "Malformed armor: {}", e),
+            ArmorError::MalformedChecksum => write!(f, "Malformed armor checksum line"),
+            ArmorError::MissingChecksum => write!(f, "Missing armor checksum"),
+            ArmorError::ChecksumMismatch { expected, actual } => {
+                write!(f, "Armor checksum mismatch: expected {:06X}, got {:06X}", expected, actual)
+            }
+            ArmorError::Timeout => write!(f, "timeout: check took too long"),
+        }
+    }
+}
+
+/// Locates the armored block with the given label (e.g. `PGP MESSAGE`) in `text`
+/// and parses its headers.
+///
+/// Both CRLF and LF line endings are accepted. Header keys are matched against
+/// `allowed_headers` case-insensitively.
+pub fn parse<'a>(
+    text: &'a str,
+    label: &str,
+    allowed_headers: &[String],
+    policy: &ArmorPolicy,
+) -> Result<Armor<'a>, ArmorError> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+
+    let start = text.find(&begin).ok_or_else(|| ArmorError::MissingBegin(label.to_string()))?;
+    let rest = &text[start + begin.len()..];
+    let end_idx = rest.find(&end).ok_or_else(|| ArmorError::MissingEnd(label.to_string()))?;
+    let mut block = &rest[..end_idx];
+
+    // Remainder of the armor header line.
+    match block.split_once('\n') {
+        Some((line, rest)) if line.trim().is_empty() => block = rest,
+        _ => return Err(ArmorError::MalformedHeader),
+    }
+
+    let mut headers = Vec::new();
+    loop {
+        let (line, rest) = block.split_once('\n').unwrap_or((block, ""));
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        if line.trim().is_empty() {
+            // Blank line separating the headers from the data.
+            block = rest;
+            break;
+        }
+        // ':' is not part of the Radix-64 alphabet, so a line without it starts the data.
+        let Some((key, value)) = line.split_once(':') else {
+            break;
+        };
+
+        if line.len() > policy.max_header_length {
+            return Err(ArmorError::HeaderTooLong);
+        }
+        if headers.len() >= policy.max_headers {
+            return Err(ArmorError::TooManyHeaders);
+        }
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(ArmorError::MalformedHeader);
+        }
+        if !allowed_headers.iter().any(|h| h.eq_ignore_ascii_case(key)) {
+            return Err(ArmorError::HeaderNotAllowed(key.to_string()));
+        }
+        headers.push((key, value.trim()));
+        block = rest;
+    }
+
+    Ok(Armor { headers, body: block })
+}
+
+/// Length of a Radix-64 line in canonical armor.
+const LINE_WIDTH: usize = 64;
+
+/// Encodes `data` as canonical armor: no headers, fixed line width, CRLF line
+/// endings and a CRC24 checksum line.
+pub fn encode(label: &str, data: &[u8]) -> String {
+    let encoded = general_purpose::STANDARD.encode(data);
+    let crc = crc24(data).to_be_bytes();
+
+    let mut armor = format!("-----BEGIN {}-----\r\n\r\n", label);
+    for line in encoded.as_bytes().chunks(LINE_WIDTH) {
+        // Radix-64 output is ASCII, so every chunk is valid UTF-8.
+        armor.push_str(std::str::from_utf8(line).unwrap_or_default());
+        armor.push_str("\r\n");
+    }
+    armor.push('=');
+    armor.push_str(&general_purpose::STANDARD.encode(&crc[1..]));
+    armor.push_str(&format!("\r\n-----END {}-----", label));
+    armor
+}
+
+/// Splits the armor body into the Radix-64 data and the optional checksum line.
+///
+/// The checksum is the last non-empty line if it starts with `=`. Padding `=`
+/// characters at the end of a data line are not mistaken for a checksum.
+pub fn split_checksum(body: &str) -> (&str, Option<&str>) {
+    let body = body.trim_end();
+    let (data, last_line) = match body.rfind('\n') {
+        Some(idx) => (&body[..idx], body[idx + 1..].trim()),
+        None => ("", body.trim()),
+    };
+    match last_line.strip_prefix('=') {
+        Some(checksum) => (data, Some(checksum)),
+        None => (body, None),
+    }
+}
+
+/// Compares the checksum line, if any, with the CRC24 of the decoded data.
+pub fn verify_checksum(checksum: Option<&str>, actual: u32, policy: &ArmorPolicy) -> Result<(), ArmorError> {
+    match checksum {
+        Some(checksum) => {
+            let octets = general_purpose::STANDARD.decode(checksum).ok()
+                .filter(|octets| checksum.len() == 4 && octets.len() == 3)
+                .ok_or(ArmorError::MalformedChecksum)?;
+            let expected = u32::from_be_bytes([0, octets[0], octets[1], octets[2]]);
+            if expected != actual {
+                return Err(ArmorError::ChecksumMismatch { expected, actual });
+            }
+            Ok(())
+        }
+        None if !policy.allow_missing_checksum => Err(ArmorError::MissingChecksum),
+        None => Ok(()),
+    }
+}
+
+/// Decodes Radix-64 text in chunks, skipping whitespace.
+pub struct Radix64Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    acc: u32,
+    sextets: u8,
+    padding: u8,
+    /// Set after a padded quantum, when only whitespace may follow.
+    finished: bool,
+}
+
+impl<'a> Radix64Decoder<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self { input: input.as_bytes(), pos: 0, acc: 0, sextets: 0, padding: 0, finished: false }
+    }
+
+    /// Decodes into `buf`, returning the number of octets written or 0 at the end of input.
+    /// `buf` must hold at least 3 octets.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, ArmorError> {
+        let mut n = 0;
+        while n + 3 <= buf.len() && self.pos < self.input.len() {
+            let c = self.input[self.pos];
+            self.pos += 1;
+
+            // Some implementations might have whitespace/newlines in base64
+            if c.is_ascii_whitespace() {
+                continue;
+            }
+            if self.finished {
+                return Err(ArmorError::Malformed("data after padding".to_string()));
+            }
+
+            let value = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                b'=' if self.sextets >= 2 => {
+                    self.padding += 1;
+                    0
+                }
+                _ => return Err(ArmorError::Malformed(format!("invalid character {:?}", c as char))),
+            };
+            if c != b'=' && self.padding > 0 {
+                return Err(ArmorError::Malformed("data after padding".to_string()));
+            }
+
+            self.acc = (self.acc << 6) | value as u32;
+            self.sextets += 1;
+            if self.sextets == 4 {
+                let octets = [(self.acc >> 16) as u8, (self.acc >> 8) as u8, self.acc as u8];
+                let len = 3 - self.padding as usize;
+                buf[n..n + len].copy_from_slice(&octets[..len]);
+                n += len;
+                self.finished = self.padding > 0;
+                self.acc = 0;
+                self.sextets = 0;
+            }
+        }
+
+        if self.pos >= self.input.len() && self.sextets != 0 {
+            return Err(ArmorError::Malformed("incomplete final quantum".to_string()));
+        }
+        Ok(n)
+    }
+}
+
+/// Decodes the Radix-64 body of an armored message and verifies its checksum.
+pub fn decode_body(body: &str, policy: &ArmorPolicy, deadline: Deadline) -> Result<Vec<u8>, ArmorError> {
+    let (data, checksum) = split_checksum(body);
+
+    let mut decoder = Radix64Decoder::new(data);
+    let mut decoded = Vec::with_capacity(data.len() / 4 * 3);
+    let mut buf = [0u8; 3072];
+    loop {
+        if deadline.is_expired() {
+            return Err(ArmorError::Timeout);
+        }
+        let n = decoder.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        decoded.extend_from_slice(&buf[..n]);
+    }
+
+    verify_checksum(checksum, crc24(&decoded), policy)?;
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Decodes `text` with a [`Radix64Decoder`] reading into a buffer of `buf_len` octets.
+    fn decode_streaming(text: &str, buf_len: usize) -> Result<Vec<u8>, ArmorError> {
+        let mut decoder = Radix64Decoder::new(text);
+        let mut buf = vec![0u8; buf_len];
+        let mut decoded = Vec::new();
+        loop {
+            let n = decoder.read(&mut buf)?;
+            if n == 0 {
+                return Ok(decoded);
+            }
+            decoded.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[test]
+    fn crc24_check_value() {
+        assert_eq!(crc24(b""), CRC24_INIT);
+        assert_eq!(crc24(b"123456789"), 0x21CF02);
+    }
+
+    #[test]
+    fn rejects_data_after_padding() {
+        assert!(decode_streaming("AA==AAAA", 3).is_err());
+        assert!(decode_streaming("AA=A", 3).is_err());
+        assert!(decode_streaming("A===", 3).is_err());
+        assert!(decode_streaming("AAA", 3).is_err());
+    }
+
+    #[test]
+    fn split_checksum_ignores_padding_on_data_line() {
+        assert_eq!(split_checksum("AA==\r\n"), ("AA==", None));
+        assert_eq!(split_checksum("AAAA\r\n=Iw/I\r\n"), ("AAAA\r", Some("Iw/I")));
+    }
+
+    proptest! {
+        #[test]
+        fn decoder_matches_base64(
+            data in prop::collection::vec(any::<u8>(), 0..2000),
+            buf_len in 3usize..100,
+            line_width in 1usize..80,
+        ) {
+            let encoded = general_purpose::STANDARD.encode(&data);
+            let wrapped: Vec<&str> = encoded
+                .as_bytes()
+                .chunks(line_width)
+                .map(|line| std::str::from_utf8(line).unwrap())
+                .collect();
+            prop_assert_eq!(decode_streaming(&wrapped.join("\r\n"), buf_len), Ok(data));
+        }
+
+        #[test]
+        fn encode_round_trips(data in prop::collection::vec(any::<u8>(), 0..2000)) {
+            let armor = encode("PGP MESSAGE", &data);
+            let parsed = parse(&armor, "PGP MESSAGE", &[], &ArmorPolicy::default()).unwrap();
+            prop_assert!(parsed.headers.is_empty());
+            let policy = ArmorPolicy { allow_missing_checksum: false, ..ArmorPolicy::default() };
+            prop_assert_eq!(decode_body(parsed.body, &policy, Deadline::NONE), Ok(data));
+        }
+
+        #[test]
+        fn checksum_mismatch_is_detected(
+            data in prop::collection::vec(any::<u8>(), 1..500),
+            flip in any::<prop::sample::Index>(),
+        ) {
+            let mut corrupted = data.clone();
+            corrupted[flip.index(data.len())] ^= 0x01;
+            let armor = encode("PGP MESSAGE", &data);
+            let checksum = armor.lines().find_map(|line| line.strip_prefix('=')).unwrap();
+            let result = verify_checksum(Some(checksum), crc24(&corrupted), &ArmorPolicy::default());
+            let mismatch = matches!(result, Err(ArmorError::ChecksumMismatch { .. }));
+            prop_assert!(mismatch);
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+/// Point in time after which a running check gives up.
+///
+/// Checks run on blocking threads that cannot be cancelled from outside, so
+/// their long loops poll the deadline and stop on their own.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    /// A deadline that never expires.
+    #[cfg(test)]
+    pub const NONE: Deadline = Deadline(None);
+
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now().checked_add(timeout))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.0.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Fails once the deadline has passed.
+    pub fn check(&self) -> Result<(), String> {
+        if self.is_expired() {
+            return Err("check took too long".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Bounded pool for CPU-bound message checks.
+///
+/// Checks run on tokio's blocking threads so they never stall the SMTP
+/// dialogue of other sessions. At most `max_concurrent` checks run at once and
+/// at most `max_queued` wait for a slot; anything beyond that is refused so the
+/// client can retry later.
+pub struct CheckPool {
+    permits: Arc<Semaphore>,
+    queued: AtomicUsize,
+    max_queued: usize,
+}
+
+/// Decrements the queue length when a waiting check gets a slot or gives up.
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl CheckPool {
+    pub fn new(max_concurrent: usize, max_queued: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent.max(1))),
+            queued: AtomicUsize::new(0),
+            max_queued,
+        }
+    }
+
+    /// Runs `check` on the blocking pool once a slot is free.
+    ///
+    /// Returns `None` if the queue is full. A caller that stops waiting before
+    /// the check started gives up its place in the queue. Once started, the
+    /// slot stays taken until `check` returns, even if the caller stops
+    /// waiting for it, so long checks should stop at a [`Deadline`].
+    pub async fn spawn<T, F>(&self, check: F) -> Option<JoinHandle<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let permit = match Arc::clone(&self.permits).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                if self.queued.fetch_add(1, Ordering::Relaxed) >= self.max_queued {
+                    self.queued.fetch_sub(1, Ordering::Relaxed);
+                    return None;
+                }
+                let _slot = QueueSlot(&self.queued);
+                Arc::clone(&self.permits).acquire_owned().await.ok()?
+            }
+        };
+        Some(tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            check()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn deadline_expires() {
+        assert!(Deadline::after(Duration::ZERO).is_expired());
+        assert!(Deadline::after(Duration::ZERO).check().is_err());
+        assert!(!Deadline::after(Duration::from_secs(60)).is_expired());
+        assert!(!Deadline::NONE.is_expired());
+    }
+
+    #[tokio::test]
+    async fn queue_is_bounded_and_abandoned_waits_leave_it() {
+        let pool = Arc::new(CheckPool::new(1, 1));
+        let (release, blocked) = mpsc::channel::<()>();
+        let running = pool.spawn(move || blocked.recv().ok()).await.unwrap();
+
+        // A caller that stops waiting for the busy slot gives up its place in the queue.
+        let abandoned = tokio::time::timeout(Duration::from_millis(50), pool.spawn(|| ())).await;
+        assert!(abandoned.is_err());
+        assert_eq!(pool.queued.load(Ordering::Relaxed), 0);
+
+        // One check may wait for the slot, the next is refused.
+        let queued = tokio::spawn({
+            let pool = Arc::clone(&pool);
+            async move { pool.spawn(|| ()).await.is_some() }
+        });
+        while pool.queued.load(Ordering::Relaxed) == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert!(pool.spawn(|| ()).await.is_none());
+
+        release.send(()).unwrap();
+        assert_eq!(running.await.unwrap(), Some(()));
+        assert!(queued.await.unwrap());
+    }
+}
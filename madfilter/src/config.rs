@@ -1,4 +1,9 @@
+use crate::armor::ArmorPolicy;
+use crate::filter::{MimeLimits, OpenPgpPolicy};
+use crate::proxy_protocol::TrustedSource;
+use crate::transport::ListenAddr;
 use configparser::ini::Ini;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 
 #[allow(dead_code)]
@@ -9,11 +14,54 @@ pub struct Config {
     pub max_message_size: usize,
     pub passthrough_senders: Vec<String>,
     pub passthrough_recipients: Vec<String>,
+    /// Local parts of RFC 2142 role mailboxes on `mail_domain`, lowercased.
+    pub role_mailboxes: Vec<String>,
+    /// Domains outgoing envelope senders must belong to, lowercased.
+    pub sender_domains: Vec<String>,
+    /// Outgoing envelope senders accepted regardless of their domain, lowercased.
+    /// The null sender is stored as an empty string.
+    pub sender_allowlist: Vec<String>,
     pub filtermail_smtp_port: u16,
     pub filtermail_smtp_port_incoming: u16,
     pub postfix_reinject_port: u16,
     pub postfix_reinject_port_incoming: u16,
     pub mailboxes_dir: PathBuf,
+    pub filtermail_smtp_socket: Option<PathBuf>,
+    pub filtermail_smtp_socket_incoming: Option<PathBuf>,
+    pub filtermail_socket_owner: Option<String>,
+    pub filtermail_socket_group: Option<String>,
+    pub filtermail_socket_mode: u32,
+    pub postfix_reinject_socket: Option<PathBuf>,
+    pub postfix_reinject_socket_incoming: Option<PathBuf>,
+    pub disable_ipv6: bool,
+    pub filtermail_listen: Vec<ListenAddr>,
+    pub filtermail_listen_incoming: Vec<ListenAddr>,
+    pub metrics_log_interval: u64,
+    pub filtermail_proxy_protocol: bool,
+    pub filtermail_proxy_protocol_trusted: Vec<TrustedSource>,
+    /// Messages per minute from one client address taken from a PROXY header, 0 for no limit.
+    pub max_client_send_per_minute: u32,
+    pub openpgp_policy: OpenPgpPolicy,
+    pub pkesk_check_outgoing: bool,
+    pub pkesk_max_extra: usize,
+    pub normalize_outgoing_armor: bool,
+    pub smime_incoming: bool,
+    pub smime_outgoing: bool,
+    pub inline_pgp_incoming: bool,
+    pub list_footer_incoming: bool,
+    pub list_footer_max_size: usize,
+    pub securejoin_max_per_hour: u32,
+    pub securejoin_max_recipients_per_hour: u32,
+    pub securejoin_max_message_size: usize,
+    pub bounce_max_text_size: usize,
+    pub bounce_max_returned_size: usize,
+    pub mime_limits: MimeLimits,
+    /// Seconds a single message may take to parse and check.
+    pub message_check_timeout: u64,
+    /// Messages checked at the same time, shared by both modes.
+    pub max_concurrent_checks: usize,
+    /// Messages waiting for a free check slot before new ones are deferred.
+    pub max_queued_checks: usize,
 }
 
 impl Config {
@@ -39,6 +87,24 @@ impl Config {
             .map(|v: String| v.split_whitespace().map(|s| s.to_string()).collect::<Vec<_>>())
             .unwrap_or_default();
 
+        let role_mailboxes = conf.get("params", "role_mailboxes")
+            .unwrap_or_else(|| "postmaster abuse".to_string())
+            .split_whitespace()
+            .map(|s| s.to_lowercase())
+            .collect::<Vec<_>>();
+
+        let sender_domains = conf.get("params", "sender_domains")
+            .unwrap_or_else(|| mail_domain.clone())
+            .split_whitespace()
+            .map(|s| s.to_lowercase())
+            .collect::<Vec<_>>();
+
+        let sender_allowlist = conf.get("params", "sender_allowlist")
+            .unwrap_or_else(|| "<>".to_string())
+            .split_whitespace()
+            .map(|s| if s == "<>" { String::new() } else { s.to_lowercase() })
+            .collect::<Vec<_>>();
+
         let filtermail_smtp_port = conf.getuint("params", "filtermail_smtp_port")
             .unwrap_or(None)
             .ok_or_else(|| anyhow::anyhow!("filtermail_smtp_port not found"))? as u16;
@@ -59,23 +125,374 @@ impl Config {
             .unwrap_or_else(|| format!("/home/vmail/mail/{}", mail_domain));
         let mailboxes_dir = PathBuf::from(mailboxes_dir_str);
 
+        // Unix domain sockets take precedence over the TCP ports when set.
+        let filtermail_smtp_socket = conf.get("params", "filtermail_smtp_socket").map(PathBuf::from);
+        let filtermail_smtp_socket_incoming = conf.get("params", "filtermail_smtp_socket_incoming").map(PathBuf::from);
+        let filtermail_socket_owner = conf.get("params", "filtermail_socket_owner");
+        let filtermail_socket_group = conf.get("params", "filtermail_socket_group");
+
+        let filtermail_socket_mode = match conf.get("params", "filtermail_socket_mode") {
+            Some(v) => u32::from_str_radix(v.trim_start_matches("0o"), 8)
+                .map_err(|_| anyhow::anyhow!("filtermail_socket_mode must be an octal number: {}", v))?,
+            None => 0o660,
+        };
+
+        let postfix_reinject_socket = conf.get("params", "postfix_reinject_socket").map(PathBuf::from);
+        let postfix_reinject_socket_incoming = conf.get("params", "postfix_reinject_socket_incoming").map(PathBuf::from);
+
+        let disable_ipv6 = conf.getboolcoerce("params", "disable_ipv6")
+            .unwrap_or(Some(false))
+            .unwrap_or(false);
+
+        let filtermail_listen = parse_listen_addrs(
+            conf.get("params", "filtermail_listen"),
+            filtermail_smtp_port,
+            filtermail_smtp_socket.is_some(),
+            disable_ipv6,
+        )?;
+        let filtermail_listen_incoming = parse_listen_addrs(
+            conf.get("params", "filtermail_listen_incoming"),
+            filtermail_smtp_port_incoming,
+            filtermail_smtp_socket_incoming.is_some(),
+            disable_ipv6,
+        )?;
+
+        let metrics_log_interval = conf.getuint("params", "metrics_log_interval")
+            .unwrap_or(Some(300))
+            .unwrap_or(300);
+
+        let filtermail_proxy_protocol = conf.getboolcoerce("params", "filtermail_proxy_protocol")
+            .unwrap_or(Some(false))
+            .unwrap_or(false);
+
+        let filtermail_proxy_protocol_trusted = conf.get("params", "filtermail_proxy_protocol_trusted")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(TrustedSource::parse)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if filtermail_proxy_protocol && filtermail_proxy_protocol_trusted.is_empty() {
+            anyhow::bail!("filtermail_proxy_protocol requires filtermail_proxy_protocol_trusted");
+        }
+
+        let max_client_send_per_minute = conf.getuint("params", "max_client_send_per_minute")
+            .unwrap_or(Some(0))
+            .unwrap_or(0) as u32;
+
+        let default_policy = OpenPgpPolicy::default();
+        let getbool = |key: &str, default: bool| {
+            conf.getboolcoerce("params", key).unwrap_or(Some(default)).unwrap_or(default)
+        };
+        let openpgp_policy = OpenPgpPolicy {
+            allow_old_format: getbool("openpgp_allow_old_format", default_policy.allow_old_format),
+            skip_marker: getbool("openpgp_skip_marker", default_policy.skip_marker),
+            allow_aead: getbool("openpgp_allow_aead", default_policy.allow_aead),
+            allow_skesk_only: getbool("openpgp_allow_skesk_only", default_policy.allow_skesk_only),
+            min_session_keys: conf.getuint("params", "openpgp_min_session_keys")
+                .unwrap_or(None)
+                .map_or(default_policy.min_session_keys, |v| v as usize),
+            max_session_keys: conf.getuint("params", "openpgp_max_session_keys")
+                .unwrap_or(None)
+                .map(|v| v as usize),
+            armor: ArmorPolicy {
+                allow_missing_checksum: getbool(
+                    "armor_allow_missing_checksum",
+                    default_policy.armor.allow_missing_checksum,
+                ),
+                headers_incoming: conf.get("params", "armor_headers_incoming")
+                    .map(|v: String| v.split_whitespace().map(|s| s.to_string()).collect::<Vec<_>>())
+                    .unwrap_or(default_policy.armor.headers_incoming),
+                headers_outgoing: conf.get("params", "armor_headers_outgoing")
+                    .map(|v: String| v.split_whitespace().map(|s| s.to_string()).collect::<Vec<_>>())
+                    .unwrap_or(default_policy.armor.headers_outgoing),
+                max_headers: conf.getuint("params", "armor_max_headers")
+                    .unwrap_or(None)
+                    .map_or(default_policy.armor.max_headers, |v| v as usize),
+                max_header_length: conf.getuint("params", "armor_max_header_length")
+                    .unwrap_or(None)
+                    .map_or(default_policy.armor.max_header_length, |v| v as usize),
+            },
+        };
+
+        let pkesk_check_outgoing = getbool("pkesk_check_outgoing", false);
+        let pkesk_max_extra = conf.getuint("params", "pkesk_max_extra")
+            .unwrap_or(Some(5))
+            .unwrap_or(5) as usize;
+
+        let normalize_outgoing_armor = getbool("normalize_outgoing_armor", false);
+
+        let smime_incoming = getbool("smime_incoming", false);
+        let smime_outgoing = getbool("smime_outgoing", false);
+        let inline_pgp_incoming = getbool("inline_pgp_incoming", false);
+
+        let list_footer_incoming = getbool("list_footer_incoming", false);
+        let list_footer_max_size = conf.getuint("params", "list_footer_max_size")
+            .unwrap_or(Some(4096))
+            .unwrap_or(4096) as usize;
+
+        let securejoin_max_per_hour = conf.getuint("params", "securejoin_max_per_hour")
+            .unwrap_or(Some(20))
+            .unwrap_or(20) as u32;
+        let securejoin_max_recipients_per_hour = conf.getuint("params", "securejoin_max_recipients_per_hour")
+            .unwrap_or(Some(10))
+            .unwrap_or(10) as u32;
+        let securejoin_max_message_size = conf.getuint("params", "securejoin_max_message_size")
+            .unwrap_or(Some(65536))
+            .unwrap_or(65536) as usize;
+
+        let bounce_max_text_size = conf.getuint("params", "bounce_max_text_size")
+            .unwrap_or(Some(16384))
+            .unwrap_or(16384) as usize;
+        let bounce_max_returned_size = conf.getuint("params", "bounce_max_returned_size")
+            .unwrap_or(Some(65536))
+            .unwrap_or(65536) as usize;
+
+        let getusize = |key: &str, default: usize| {
+            conf.getuint("params", key).unwrap_or(None).map_or(default, |v| v as usize)
+        };
+        let mime_limits = MimeLimits {
+            max_depth: getusize("mime_max_depth", 20),
+            max_parts: getusize("mime_max_parts", 500),
+            max_headers: getusize("mime_max_headers", 500),
+            max_decoded_size: getusize("mime_max_decoded_size", max_message_size),
+        };
+        let message_check_timeout = conf.getuint("params", "message_check_timeout")
+            .unwrap_or(Some(10))
+            .unwrap_or(10);
+        let default_checks = std::thread::available_parallelism().map_or(4, |n| n.get());
+        let max_concurrent_checks = getusize("max_concurrent_checks", default_checks);
+        let max_queued_checks = getusize("max_queued_checks", 64);
+
         Ok(Config {
             mail_domain,
             max_user_send_per_minute,
             max_message_size,
             passthrough_senders,
             passthrough_recipients,
+            role_mailboxes,
+            sender_domains,
+            sender_allowlist,
             filtermail_smtp_port,
             filtermail_smtp_port_incoming,
             postfix_reinject_port,
             postfix_reinject_port_incoming,
             mailboxes_dir,
+            filtermail_smtp_socket,
+            filtermail_smtp_socket_incoming,
+            filtermail_socket_owner,
+            filtermail_socket_group,
+            filtermail_socket_mode,
+            postfix_reinject_socket,
+            postfix_reinject_socket_incoming,
+            disable_ipv6,
+            filtermail_listen,
+            filtermail_listen_incoming,
+            metrics_log_interval,
+            filtermail_proxy_protocol,
+            filtermail_proxy_protocol_trusted,
+            max_client_send_per_minute,
+            openpgp_policy,
+            pkesk_check_outgoing,
+            pkesk_max_extra,
+            normalize_outgoing_armor,
+            smime_incoming,
+            smime_outgoing,
+            inline_pgp_incoming,
+            list_footer_incoming,
+            list_footer_max_size,
+            securejoin_max_per_hour,
+            securejoin_max_recipients_per_hour,
+            securejoin_max_message_size,
+            bounce_max_text_size,
+            bounce_max_returned_size,
+            mime_limits,
+            message_check_timeout,
+            max_concurrent_checks,
+            max_queued_checks,
+        })
+    }
+
+    /// Whether a connection from `ip` must start with a PROXY protocol header.
+    pub fn expects_proxy_header(&self, ip: IpAddr) -> bool {
+        self.filtermail_proxy_protocol
+            && self.filtermail_proxy_protocol_trusted.iter().any(|t| t.contains(ip))
+    }
+
+    /// Whether S/MIME encrypted mail counts as encrypted in the given direction.
+    pub fn accepts_smime(&self, outgoing: bool) -> bool {
+        if outgoing { self.smime_outgoing } else { self.smime_incoming }
+    }
+
+    /// Whether `addr` is one of the configured role mailboxes of `mail_domain`,
+    /// which may always send and receive cleartext.
+    pub fn is_role_mailbox(&self, addr: &str) -> bool {
+        addr.rsplit_once('@').is_some_and(|(local, domain)| {
+            domain.eq_ignore_ascii_case(&self.mail_domain)
+                && self.role_mailboxes.iter().any(|r| r.eq_ignore_ascii_case(local))
         })
     }
 
+    /// Whether outgoing mail may use `mail_from` as envelope sender.
+    pub fn is_outgoing_sender_allowed(&self, mail_from: &str) -> bool {
+        if self.is_allowlisted_sender(mail_from) {
+            return true;
+        }
+        let mail_from = mail_from.trim().to_lowercase();
+        mail_from.rsplit_once('@').is_some_and(|(local, domain)| {
+            !local.is_empty() && self.sender_domains.iter().any(|d| d == domain)
+        })
+    }
+
+    /// Whether `mail_from` is a system sender from `sender_allowlist`, such as the null sender.
+    pub fn is_allowlisted_sender(&self, mail_from: &str) -> bool {
+        self.sender_allowlist.contains(&mail_from.trim().to_lowercase())
+    }
+
     pub fn is_incoming_cleartext_ok(&self, addr: &str) -> bool {
+        if self.is_role_mailbox(addr) {
+            return true;
+        }
         let user_dir = self.mailboxes_dir.join(addr);
         let enforce_path = user_dir.join("enforceE2EEincoming");
         !enforce_path.exists()
     }
 }
+
+/// Parses a whitespace separated list of listen addresses.
+///
+/// Without an explicit list the filter listens on `127.0.0.1`, unless a
+/// Unix socket is configured for the mode, in which case it only listens there.
+fn parse_listen_addrs(
+    value: Option<String>,
+    port: u16,
+    has_socket: bool,
+    disable_ipv6: bool,
+) -> anyhow::Result<Vec<ListenAddr>> {
+    let value = match value {
+        Some(v) => v,
+        None if has_socket => return Ok(Vec::new()),
+        None => "127.0.0.1".to_string(),
+    };
+
+    let mut addrs = Vec::new();
+    for entry in value.split_whitespace() {
+        let addr = ListenAddr::parse(entry, port)?;
+        if disable_ipv6 {
+            match addr.without_ipv6() {
+                Some(addr) if !addrs.contains(&addr) => addrs.push(addr),
+                Some(_) => {}
+                None => eprintln!("Skipping IPv6 listen address {} because disable_ipv6 is set", addr),
+            }
+        } else if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    Ok(addrs)
+}
+
+#[cfg(test)]
+impl Config {
+    /// Loads a config for `example.org` with the required ports and the given
+    /// extra `[params]` lines.
+    pub fn for_tests(params: &str) -> Self {
+        use std::io::Write;
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            "[params]\nmail_domain = example.org\nfiltermail_smtp_port = 10080\n\
+            filtermail_smtp_port_incoming = 10081\npostfix_reinject_port = 10025\n\
+            postfix_reinject_port_incoming = 10026\n{params}\n"
+        )
+        .unwrap();
+        Config::from_file(file.path()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listen(params: &str) -> (Vec<String>, Vec<String>) {
+        let config = Config::for_tests(params);
+        let strings = |addrs: &[ListenAddr]| addrs.iter().map(ToString::to_string).collect();
+        (strings(&config.filtermail_listen), strings(&config.filtermail_listen_incoming))
+    }
+
+    #[test]
+    fn listen_addrs() {
+        assert_eq!(listen(""), (vec!["127.0.0.1:10080".to_string()], vec!["127.0.0.1:10081".to_string()]));
+        let (outgoing, incoming) = listen(
+            "filtermail_listen = 127.0.0.1 [::1] [fd00::1]:2525 127.0.0.1:10080\nfiltermail_listen_incoming = *",
+        );
+        assert_eq!(outgoing, ["127.0.0.1:10080", "[::1]:10080", "[fd00::1]:2525"]);
+        assert_eq!(incoming, ["[::]:10081 (dual-stack)"]);
+
+        // With IPv6 disabled, IPv6 entries are dropped and the wildcard listens on IPv4 only.
+        let (outgoing, incoming) = listen(
+            "disable_ipv6 = true\nfiltermail_listen = 127.0.0.1 [::1] [fd00::1]:2525\n\
+            filtermail_listen_incoming = * 0.0.0.0",
+        );
+        assert_eq!(outgoing, ["127.0.0.1:10080"]);
+        assert_eq!(incoming, ["0.0.0.0:10081"]);
+
+        // A socket replaces the default address but not an explicit list.
+        let socket = "filtermail_smtp_socket = /run/filtermail.sock";
+        let (outgoing, incoming) = listen(&format!("{socket}\nfiltermail_listen_incoming = ::1"));
+        assert!(outgoing.is_empty());
+        assert_eq!(incoming, ["[::1]:10081"]);
+        let (outgoing, _) = listen(&format!("{socket}\nfiltermail_listen = 127.0.0.1"));
+        assert_eq!(outgoing, ["127.0.0.1:10080"]);
+
+        assert!(parse_listen_addrs(Some("127.0.0.1 nowhere".to_string()), 10080, false, false).is_err());
+    }
+
+    #[test]
+    fn role_mailboxes() {
+        let config = Config::for_tests("");
+        assert!(config.is_role_mailbox("postmaster@example.org"));
+        assert!(config.is_role_mailbox("abuse@example.org"));
+        assert!(config.is_role_mailbox("PostMaster@Example.ORG"));
+        assert!(!config.is_role_mailbox("postmaster@example.com"));
+        assert!(!config.is_role_mailbox("postmaster@sub.example.org"));
+        assert!(!config.is_role_mailbox("postmaster"));
+        assert!(!config.is_role_mailbox("hostmaster@example.org"));
+        assert!(!config.is_role_mailbox("postmaster2@example.org"));
+        assert!(config.is_incoming_cleartext_ok("abuse@example.org"));
+
+        let config = Config::for_tests("role_mailboxes = Security hostmaster");
+        assert!(config.is_role_mailbox("security@example.org"));
+        assert!(config.is_role_mailbox("HOSTMASTER@example.org"));
+        assert!(!config.is_role_mailbox("postmaster@example.org"));
+        assert!(!config.is_role_mailbox("security@example.net"));
+    }
+
+    #[test]
+    fn outgoing_senders() {
+        let config = Config::for_tests("");
+        assert!(config.is_outgoing_sender_allowed("alice@example.org"));
+        assert!(config.is_outgoing_sender_allowed("Alice@Example.ORG"));
+        assert!(config.is_outgoing_sender_allowed(" alice@example.org "));
+        assert!(!config.is_outgoing_sender_allowed("alice@example.com"));
+        assert!(!config.is_outgoing_sender_allowed("alice@sub.example.org"));
+        assert!(!config.is_outgoing_sender_allowed("alice@example.org.evil"));
+        assert!(!config.is_outgoing_sender_allowed("@example.org"));
+        assert!(!config.is_outgoing_sender_allowed("alice"));
+        // The null sender of bounces is allowlisted by default.
+        assert!(config.is_outgoing_sender_allowed(""));
+        assert!(config.is_allowlisted_sender(""));
+        assert!(!config.is_allowlisted_sender("alice@example.org"));
+        assert!(!config.is_outgoing_sender_allowed("root@localhost"));
+
+        let config = Config::for_tests(
+            "sender_domains = Example.org chat.example.net\nsender_allowlist = Root@Localhost",
+        );
+        assert!(config.is_outgoing_sender_allowed("bob@CHAT.example.net"));
+        assert!(config.is_outgoing_sender_allowed("root@localhost"));
+        assert!(config.is_outgoing_sender_allowed("ROOT@localhost"));
+        assert!(config.is_allowlisted_sender("root@localhost"));
+        assert!(!config.is_outgoing_sender_allowed("bob@localhost"));
+        // Without `<>` in the allowlist the null sender is refused.
+        assert!(!config.is_outgoing_sender_allowed(""));
+    }
+}
@@ -1,234 +1,1758 @@
-use base64::{engine::general_purpose, Engine as _};
-use mail_parser::{Message, PartType, MimeHeaders};
+use crate::armor::{self, ArmorError, ArmorPolicy, Crc24, Radix64Decoder};
+use crate::check_pool::Deadline;
+use crate::openpgp::{HeaderFormat, PacketError, PacketIter, PacketStream, RecipientKey};
+use crate::smime;
+use mail_parser::{Addr, Address, HeaderName, Message, MessagePart, PartType, MimeHeaders};
+use std::fmt;
+use std::ops::Range;
 
 pub const ENCRYPTION_NEEDED_523: &str = "523 Encryption Needed: Invalid Unencrypted Mail";
+pub const SECUREJOIN_RATE_LIMITED_450: &str = "450 4.7.28 Too many Secure-Join requests, try again later";
+pub const SECUREJOIN_TOO_LARGE_552: &str = "552 5.3.4 Secure-Join message too large";
+pub const MISSING_FROM_550: &str = "550 5.6.0 Message has no From header";
+pub const MULTIPLE_FROM_550: &str = "550 5.6.0 Message has more than one From header";
+pub const FROM_NOT_SINGLE_MAILBOX_550: &str = "550 5.6.0 From header must contain exactly one mailbox";
+pub const SENDER_MISMATCH_550: &str = "550 5.7.1 Sender header does not match envelope sender";
+pub const REPLY_TO_SPOOFED_550: &str = "550 5.7.1 Reply-To display name claims a local address";
+pub const SPOOFED_DISPLAY_NAME_550: &str = "550 5.7.1 Display name contains a different address";
+pub const MIME_TOO_COMPLEX_552: &str = "552 5.3.4 Message structure too complex";
+pub const CHECK_QUEUE_FULL_451: &str = "451 4.3.2 Too many messages being checked, try again later";
+pub const CHECK_QUEUE_TIMEOUT_451: &str = "451 4.3.2 Timed out waiting to check message, try again later";
+pub const CHECK_TIMEOUT_554: &str = "554 5.3.4 Message took too long to check";
+pub const IMPLAUSIBLE_KEYS_554: &str = "554 5.7.1 Number of encryption keys does not match recipients";
 
-pub fn check_openpgp_payload(payload: &[u8]) -> bool {
-    let mut i = 0;
-    while i < payload.len() {
-        // Only OpenPGP format is allowed.
-        if payload[i] & 0xC0 != 0xC0 {
-            return false;
-        }
+/// Longest failure reason sent to the client.
+const MAX_REPLY_REASON_LENGTH: usize = 200;
 
-        let packet_type_id = payload[i] & 0x3F;
-        i += 1;
+/// Builds the multi-line [`ENCRYPTION_NEEDED_523`] reply naming the failed check.
+///
+/// The reason goes on a continuation line, so the final line stays exactly the
+/// reply clients already match on.
+pub fn encryption_needed(reason: &str) -> String {
+    let reason: String = reason
+        .chars()
+        .filter(|c| c.is_ascii_graphic() || *c == ' ')
+        .take(MAX_REPLY_REASON_LENGTH)
+        .collect();
+    if reason.is_empty() {
+        return ENCRYPTION_NEEDED_523.to_string();
+    }
+    format!("523-{}\r\n{}", reason, ENCRYPTION_NEEDED_523)
+}
 
-        if i >= payload.len() {
-            eprintln!("REJECT: OpenPGP payload: Unexpected end of payload after packet tag");
-            return false;
-        }
+/// Packet tags relevant to encrypted messages.
+const PKESK: u8 = 1;
+const SKESK: u8 = 3;
+const MARKER: u8 = 10;
+const SEIPD: u8 = 18;
+const AEAD_ENCRYPTED: u8 = 20;
+const PADDING: u8 = 21;
 
-        while payload[i] >= 224 && payload[i] < 255 {
-            // Partial body length.
-            let partial_length = 1 << (payload[i] & 0x1F);
-            i += 1 + partial_length;
-            if i >= payload.len() {
-                eprintln!("REJECT: OpenPGP payload: Unexpected end of payload during partial body length processing");
-                return false;
-            }
+/// Operator-configurable grammar of acceptable OpenPGP messages.
+#[derive(Debug, Clone)]
+pub struct OpenPgpPolicy {
+    /// Tolerate legacy (RFC 4880 "old format") packet headers.
+    pub allow_old_format: bool,
+    /// Ignore Marker packets instead of rejecting them.
+    pub skip_marker: bool,
+    /// Accept AEAD Encrypted Data packets in place of SEIPD.
+    pub allow_aead: bool,
+    /// Accept messages whose session key is only protected by a password.
+    pub allow_skesk_only: bool,
+    pub min_session_keys: usize,
+    pub max_session_keys: Option<usize>,
+    pub armor: ArmorPolicy,
+}
+
+impl Default for OpenPgpPolicy {
+    fn default() -> Self {
+        Self {
+            allow_old_format: false,
+            skip_marker: false,
+            allow_aead: false,
+            allow_skesk_only: true,
+            min_session_keys: 0,
+            max_session_keys: None,
+            armor: ArmorPolicy::default(),
         }
+    }
+}
 
-        let body_len: usize;
-        if payload[i] < 192 {
-            // One-octet length.
-            body_len = payload[i] as usize;
-            i += 1;
-        } else if payload[i] < 224 {
-            // Two-octet length.
-            if i + 1 >= payload.len() {
-                eprintln!("REJECT: OpenPGP payload: Unexpected end of payload during two-octet length processing");
-                return false;
+/// The rule of the OpenPGP packet policy a payload violates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketReject {
+    Malformed(PacketError),
+    OldFormat,
+    UnexpectedPacket(u8),
+    EmptyPacket(u8),
+    MalformedPkesk,
+    UnsupportedVersion { tag: u8, version: u8 },
+    VersionMismatch,
+    PacketAfterEncryptedData(u8),
+    MissingEncryptedData,
+    AeadNotAllowed,
+    SkeskOnly,
+    TooFewSessionKeys(usize),
+    TooManySessionKeys(usize),
+    /// The deadline of the check passed before all packets were seen.
+    Timeout,
+}
+
+impl fmt::Display for PacketReject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketReject::Malformed(e) => write!(f, "malformed: {}", e),
+            PacketReject::OldFormat => write!(f, "old-format: old format packet header"),
+            PacketReject::UnexpectedPacket(tag) => write!(f, "packet-type: unexpected packet type {}", tag),
+            PacketReject::EmptyPacket(tag) => write!(f, "empty-packet: empty packet of type {}", tag),
+            PacketReject::MalformedPkesk => write!(f, "pkesk: malformed recipient in PKESK packet"),
+            PacketReject::UnsupportedVersion { tag, version } => {
+                write!(f, "version: unsupported version {} of packet type {}", version, tag)
             }
-            body_len = (((payload[i] as usize) - 192) << 8) + (payload[i + 1] as usize) + 192;
-            i += 2;
-        } else if payload[i] == 255 {
-            // Five-octet length.
-            if i + 4 >= payload.len() {
-                return false;
+            PacketReject::VersionMismatch => {
+                write!(f, "version-mismatch: session key and encrypted data packet versions do not match")
             }
-            body_len = ((payload[i + 1] as usize) << 24)
-                | ((payload[i + 2] as usize) << 16)
-                | ((payload[i + 3] as usize) << 8)
-                | (payload[i + 4] as usize);
-            i += 5;
-        } else {
-            // Impossible, partial body length was processed above.
-            eprintln!("REJECT: OpenPGP payload: Invalid length octet value");
-            return false;
+            PacketReject::PacketAfterEncryptedData(tag) => {
+                write!(f, "packet-order: packet type {} after encrypted data", tag)
+            }
+            PacketReject::MissingEncryptedData => write!(f, "missing-encrypted-data: no encrypted data packet"),
+            PacketReject::AeadNotAllowed => write!(f, "aead: AEAD Encrypted Data packets are not allowed"),
+            PacketReject::SkeskOnly => write!(f, "skesk-only: password-only messages are not allowed"),
+            PacketReject::TooFewSessionKeys(n) => write!(f, "min-session-keys: only {} session key packets", n),
+            PacketReject::TooManySessionKeys(n) => write!(f, "max-session-keys: {} session key packets", n),
+            PacketReject::Timeout => write!(f, "timeout: check took too long"),
         }
+    }
+}
 
-        i += body_len;
+/// What validation learned about the session key packets of a message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PacketSummary {
+    /// Recipients of the PKESK packets, in message order.
+    pub pkesk_recipients: Vec<RecipientKey>,
+    pub skesk_count: usize,
+}
 
-        if i == payload.len() {
-            // Last packet should be
-            // Symmetrically Encrypted and Integrity Protected Data Packet (SEIPD)
-            return packet_type_id == 18;
-        } else if i > payload.len() {
-            return false;
-        } else if packet_type_id != 1 && packet_type_id != 3 {
-            // All packets except the last one must be either
+impl From<PacketError> for PacketReject {
+    fn from(e: PacketError) -> Self {
+        PacketReject::Malformed(e)
+    }
+}
+
+/// Applies an [`OpenPgpPolicy`] to a sequence of packets.
+///
+/// Expects session key packets (PKESK or SKESK) followed by a single
+/// encrypted data packet and optional trailing Padding packets.
+/// Version octets are validated according to RFC 9580: v3 PKESK and v4 SKESK
+/// must be followed by a v1 SEIPD, v6 PKESK and v6 SKESK by a v2 SEIPD.
+pub struct PacketValidator<'a> {
+    policy: &'a OpenPgpPolicy,
+    // Version of SEIPD required by the session key packets seen so far.
+    seipd_version: Option<u8>,
+    seen_encrypted_data: bool,
+    summary: PacketSummary,
+}
+
+impl<'a> PacketValidator<'a> {
+    pub fn new(policy: &'a OpenPgpPolicy) -> Self {
+        Self {
+            policy,
+            seipd_version: None,
+            seen_encrypted_data: false,
+            summary: PacketSummary::default(),
+        }
+    }
+
+    /// Checks the next packet. `body` holds at least the leading octets of the packet body.
+    pub fn packet(&mut self, tag: u8, format: HeaderFormat, body: &[u8]) -> Result<(), PacketReject> {
+        if format == HeaderFormat::Legacy && !self.policy.allow_old_format {
+            return Err(PacketReject::OldFormat);
+        }
+
+        if self.seen_encrypted_data {
+            // Only Padding packets may follow the encrypted data.
+            if tag != PADDING {
+                return Err(PacketReject::PacketAfterEncryptedData(tag));
+            }
+            return Ok(());
+        }
+
+        if tag == MARKER && self.policy.skip_marker {
+            return Ok(());
+        }
+        if tag == AEAD_ENCRYPTED && !self.policy.allow_aead {
+            return Err(PacketReject::AeadNotAllowed);
+        }
+        if !matches!(tag, PKESK | SKESK | SEIPD | AEAD_ENCRYPTED) {
+            // All packets before the encrypted data must be either
             // Public-Key Encrypted Session Key Packet (PKESK)
             // or
             // Symmetric-Key Encrypted Session Key Packet (SKESK)
-            return false;
+            return Err(PacketReject::UnexpectedPacket(tag));
         }
+
+        let Some(&version) = body.first() else {
+            return Err(PacketReject::EmptyPacket(tag));
+        };
+
+        // SEIPD version each session key packet version must be followed by.
+        let required_seipd_version = match (tag, version) {
+            (PKESK, 3) | (SKESK, 4) => 1,
+            (PKESK, 6) | (SKESK, 6) => 2,
+            // AEAD Encrypted Data is used with the same session key packets as SEIPD v1.
+            (SEIPD, 1) | (SEIPD, 2) | (AEAD_ENCRYPTED, 1) => {
+                let data_version = if tag == SEIPD { version } else { 1 };
+                if self.seipd_version.is_some_and(|v| v != data_version) {
+                    return Err(PacketReject::VersionMismatch);
+                }
+                self.seen_encrypted_data = true;
+                return Ok(());
+            }
+            _ => return Err(PacketReject::UnsupportedVersion { tag, version }),
+        };
+        if self.seipd_version.is_some_and(|v| v != required_seipd_version) {
+            return Err(PacketReject::VersionMismatch);
+        }
+        self.seipd_version = Some(required_seipd_version);
+
+        if tag == PKESK {
+            let recipient = RecipientKey::from_pkesk(body).ok_or(PacketReject::MalformedPkesk)?;
+            self.summary.pkesk_recipients.push(recipient);
+        } else {
+            self.summary.skesk_count += 1;
+        }
+        Ok(())
     }
 
-    false
-}
+    /// Checks the message as a whole once all packets were seen.
+    pub fn finish(self) -> Result<PacketSummary, PacketReject> {
+        // Last non-padding packet should be
+        // Symmetrically Encrypted and Integrity Protected Data Packet (SEIPD)
+        if !self.seen_encrypted_data {
+            return Err(PacketReject::MissingEncryptedData);
+        }
 
-pub fn check_armored_payload(payload: &str, outgoing: bool) -> bool {
-    let prefix = "-----BEGIN PGP MESSAGE-----";
-    if !payload.contains(prefix) {
-        eprintln!("REJECT: Missing BEGIN PGP MESSAGE prefix");
-        return false;
+        let pkesk_count = self.summary.pkesk_recipients.len();
+        let skesk_count = self.summary.skesk_count;
+        if pkesk_count == 0 && skesk_count > 0 && !self.policy.allow_skesk_only {
+            return Err(PacketReject::SkeskOnly);
+        }
+        let session_keys = pkesk_count + skesk_count;
+        if session_keys < self.policy.min_session_keys {
+            return Err(PacketReject::TooFewSessionKeys(session_keys));
+        }
+        if self.policy.max_session_keys.is_some_and(|max| session_keys > max) {
+            return Err(PacketReject::TooManySessionKeys(session_keys));
+        }
+
+        Ok(self.summary)
     }
-    let start_idx = payload.find(prefix).unwrap();
-    let mut payload = &payload[start_idx + prefix.len()..];
-    
-    // Skip any header whitespace
-    payload = payload.trim_start();
+}
 
-    let suffix = "-----END PGP MESSAGE-----";
-    if !payload.contains(suffix) {
-        eprintln!("REJECT: Missing END PGP MESSAGE suffix");
-        return false;
+/// Checks that `payload` is an encrypted OpenPGP message acceptable under `policy`.
+pub fn check_openpgp_payload(
+    payload: &[u8],
+    policy: &OpenPgpPolicy,
+    deadline: Deadline,
+) -> Result<PacketSummary, PacketReject> {
+    let mut validator = PacketValidator::new(policy);
+    for header in PacketIter::new(payload) {
+        if deadline.is_expired() {
+            return Err(PacketReject::Timeout);
+        }
+        let header = header?;
+        validator.packet(header.tag, header.format, &payload[header.body_range])?;
     }
-    let end_idx = payload.find(suffix).unwrap();
-    payload = &payload[..end_idx];
+    validator.finish()
+}
 
-    let version_comment = "Version: ";
-    if payload.starts_with(version_comment) {
-        if outgoing {
-            // Disallow comments in outgoing messages
-            eprintln!("REJECT: Outgoing armored payload contains 'Version:' comment");
-            return false;
+const ARMOR_BEGIN: &str = "-----BEGIN PGP MESSAGE-----";
+const ARMOR_END: &str = "-----END PGP MESSAGE-----";
+
+/// Whether `text`, apart from surrounding whitespace, is exactly one armored
+/// OpenPGP message.
+fn is_sole_armored_message(text: &str) -> bool {
+    let text = text.trim();
+    text.starts_with(ARMOR_BEGIN) && text.find(ARMOR_END) == Some(text.len() - ARMOR_END.len())
+}
+
+pub fn check_armored_payload(
+    payload: &str,
+    outgoing: bool,
+    policy: &OpenPgpPolicy,
+    deadline: Deadline,
+) -> Result<PacketSummary, String> {
+    let allowed_headers = policy.armor.allowed_headers(outgoing);
+    let armor = armor::parse(payload, "PGP MESSAGE", allowed_headers, &policy.armor).map_err(|e| e.to_string())?;
+    check_armor_body(armor.body, policy, deadline)
+}
+
+/// Decodes the radix-64 body of an already parsed armor block and runs its
+/// packets through the policy.
+fn check_armor_body(body: &str, policy: &OpenPgpPolicy, deadline: Deadline) -> Result<PacketSummary, String> {
+    let (data, checksum) = armor::split_checksum(body);
+
+    // Decoded octets go straight to the packet parser, so large payloads are never copied.
+    let mut decoder = Radix64Decoder::new(data);
+    let mut buf = [0u8; 3072];
+    let mut crc = Crc24::new();
+    let mut packets = PacketStream::new();
+    let mut validator = PacketValidator::new(policy);
+    // The first packet error is only reported once the checksum was verified.
+    let mut packet_result = Ok(());
+    loop {
+        deadline.check()?;
+        let n = decoder.read(&mut buf).map_err(|e| e.to_string())?;
+        if n == 0 {
+            break;
         }
-        // Remove comments from incoming messages
-        if let Some((_, rest)) = payload.split_once("\r\n") {
-            payload = rest;
-        } else {
-            eprintln!("REJECT: Armored payload 'Version:' line without CRLF");
+        crc.update(&buf[..n]);
+        if packet_result.is_ok() {
+            packet_result = packets.feed(&buf[..n], |header, body| {
+                validator.packet(header.tag, header.format, body)
+            });
+        }
+    }
+    armor::verify_checksum(checksum, crc.finish(), &policy.armor).map_err(|e| e.to_string())?;
+
+    packet_result
+        .and_then(|_| packets.finish(|header, body| validator.packet(header.tag, header.format, body)))
+        .and_then(|_| validator.finish())
+        .map_err(|e| format!("OpenPGP payload: {}", e))
+}
+
+/// Shape of an unencrypted Secure-Join handshake message.
+struct SecureJoinVariant {
+    /// Value of the `Secure-Join` header.
+    header: &'static str,
+    /// Accepted bodies of the text part, compared case-insensitively after trimming.
+    bodies: &'static [&'static str],
+    /// Maximum size of an `application/pgp-keys` attachment, if one may follow the text part.
+    max_key_size: Option<usize>,
+}
+
+/// Upper bound for an attached public key, large enough for an RSA-4096 key
+/// with a few subkeys and signatures.
+const MAX_SECUREJOIN_KEY_SIZE: usize = 32 * 1024;
+
+/// Secure-Join steps that current Delta Chat releases send before the peers
+/// know each other's keys. All later steps are encrypted.
+const SECUREJOIN_VARIANTS: &[SecureJoinVariant] = &[
+    SecureJoinVariant {
+        header: "vc-request",
+        bodies: &["secure-join: vc-request"],
+        max_key_size: None,
+    },
+    SecureJoinVariant {
+        header: "vg-request",
+        bodies: &["secure-join: vg-request"],
+        max_key_size: None,
+    },
+    SecureJoinVariant {
+        header: "vc-request-pubkey",
+        bodies: &["secure-join: vc-request-pubkey"],
+        max_key_size: Some(MAX_SECUREJOIN_KEY_SIZE),
+    },
+];
+
+/// OpenPGP packet tags of a transferable public key.
+const SIGNATURE: u8 = 2;
+const PUBLIC_KEY: u8 = 6;
+const USER_ID: u8 = 13;
+const PUBLIC_SUBKEY: u8 = 14;
+const USER_ATTRIBUTE: u8 = 17;
+
+pub fn is_securejoin(message: &Message, deadline: Deadline) -> bool {
+    let sj_header = message.header("secure-join");
+    let sj_val = sj_header.and_then(|h| h.as_text()).map(str::trim);
+    let Some(variant) = sj_val.and_then(|v| SECUREJOIN_VARIANTS.iter().find(|s| s.header.eq_ignore_ascii_case(v)))
+    else {
+        return false;
+    };
+
+    let leaves: Vec<_> = message.parts.iter().filter(|p| {
+        p.content_type().is_none_or(|ct| ct.c_type.to_lowercase() != "multipart")
+    }).collect();
+    let (text_part, key_part) = match leaves[..] {
+        [text] => (text, None),
+        [text, key] if variant.max_key_size.is_some() => (text, Some(key)),
+        _ => {
+            eprintln!("REJECT: securejoin has {} non-multipart parts", leaves.len());
             return false;
         }
+    };
+
+    if !text_part.is_content_type("text", "plain") {
+        eprintln!("REJECT: securejoin part is not text/plain: {:?}", text_part.content_type());
+        return false;
+    }
+    let PartType::Text(text) = &text_part.body else {
+        eprintln!("REJECT: securejoin text part has no text body");
+        return false;
+    };
+    let payload = text.trim().to_lowercase();
+    if !variant.bodies.contains(&payload.as_str()) {
+        eprintln!("REJECT: securejoin invalid payload: {:.80}", payload);
+        return false;
     }
 
-    while payload.starts_with("\r\n") {
-        payload = &payload[2..];
+    if let (Some(key), Some(max_size)) = (key_part, variant.max_key_size)
+        && let Err(reason) = check_securejoin_key(key, max_size, deadline)
+    {
+        eprintln!("REJECT: securejoin key attachment: {}", reason);
+        return false;
     }
+    true
+}
 
-    // Remove CRC24.
-    if let Some((base64_part, _)) = payload.rsplit_once('=') {
-        payload = base64_part;
+/// Checks that a Secure-Join attachment is nothing but a single armored
+/// transferable public key: a primary key followed only by user IDs, user
+/// attributes, subkeys and signatures.
+fn check_securejoin_key(part: &MessagePart, max_size: usize, deadline: Deadline) -> Result<(), String> {
+    if !part.is_content_type("application", "pgp-keys") {
+        return Err(format!("not application/pgp-keys: {:?}", part.content_type()));
+    }
+    let contents = part.contents();
+    if contents.len() > max_size {
+        return Err(format!("{} bytes exceeds {}", contents.len(), max_size));
     }
 
-    // Some implementations might have whitespace/newlines in base64
-    let cleaned_payload: String = payload.chars().filter(|c| !c.is_whitespace()).collect();
+    let text = std::str::from_utf8(contents).map_err(|_| "not valid UTF-8")?.trim();
+    if !text.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----")
+        || !text.ends_with("-----END PGP PUBLIC KEY BLOCK-----")
+    {
+        return Err("text outside of the armored key".to_string());
+    }
+    let policy = ArmorPolicy::default();
+    let parsed = armor::parse(text, "PGP PUBLIC KEY BLOCK", &policy.headers_incoming, &policy)
+        .map_err(|e| e.to_string())?;
+    let key = armor::decode_body(parsed.body, &policy, deadline).map_err(|e| e.to_string())?;
 
-    match general_purpose::STANDARD.decode(cleaned_payload) {
-        Ok(decoded) => check_openpgp_payload(&decoded),
-        Err(_) => false,
+    let mut packets = PacketIter::new(&key);
+    match packets.next() {
+        Some(Ok(header)) if header.tag == PUBLIC_KEY => {}
+        Some(Ok(header)) => return Err(format!("starts with packet type {}", header.tag)),
+        Some(Err(e)) => return Err(e.to_string()),
+        None => return Err("empty key".to_string()),
     }
+    for packet in packets {
+        deadline.check()?;
+        match packet.map_err(|e| e.to_string())?.tag {
+            SIGNATURE | USER_ID | PUBLIC_SUBKEY | USER_ATTRIBUTE => {}
+            PUBLIC_KEY => return Err("more than one public key".to_string()),
+            tag => return Err(format!("unexpected packet type {} in public key", tag)),
+        }
+    }
+    Ok(())
 }
 
-pub fn is_securejoin(message: &Message) -> bool {
-    let sj_header = message.header("secure-join");
-    let sj_val = sj_header.and_then(|h| h.as_text());
-    
-    if !matches!(sj_val, Some("vc-request") | Some("vg-request")) {
-        return false;
+/// Armor headers of the encrypted key in an Autocrypt Setup Message.
+const AUTOCRYPT_SETUP_ARMOR_HEADERS: &[&str] = &["Passphrase-Format", "Passphrase-Begin"];
+
+/// Upper bound for the setup attachment, which holds the encrypted secret key.
+const MAX_AUTOCRYPT_SETUP_SIZE: usize = 128 * 1024;
+
+/// Upper bound for the human-readable description accompanying the setup
+/// attachment, and for the text around the armor inside it.
+const MAX_AUTOCRYPT_SETUP_DESCRIPTION_SIZE: usize = 4096;
+
+/// Checks the structure of an Autocrypt Setup Message (Autocrypt Level 1,
+/// section 4.4): an `Autocrypt-Setup-Message: v1` header and a `multipart/mixed`
+/// body with an optional short text description and one
+/// `application/autocrypt-setup` attachment. The attachment must hold a
+/// password-encrypted (SKESK and SEIPD only) OpenPGP message whose armor
+/// carries a `Passphrase-Format` header.
+pub fn check_autocrypt_setup(message: &Message, policy: &OpenPgpPolicy, deadline: Deadline) -> Result<(), String> {
+    let version = message.header("Autocrypt-Setup-Message").and_then(|h| h.as_text()).map(str::trim);
+    if version != Some("v1") {
+        return Err(format!("Autocrypt-Setup-Message header is not v1: {:?}", version));
+    }
+    if !message.is_content_type("multipart", "mixed") {
+        return Err("Autocrypt Setup Message is not multipart/mixed".to_string());
     }
 
-    let mut parts_count = 0;
+    let mut setup = None;
+    let mut description = None;
     for part in message.parts.iter().filter(|p| {
-        p.content_type().map_or(true, |ct| ct.c_type.to_lowercase() != "multipart")
+        p.content_type().is_none_or(|ct| ct.c_type.to_lowercase() != "multipart")
     }) {
-        parts_count += 1;
-        if parts_count > 1 {
-            eprintln!("REJECT: securejoin has more than 1 non-multipart part");
-            return false;
+        if part.is_content_type("application", "autocrypt-setup") && setup.is_none() {
+            setup = Some(part);
+        } else if part.is_content_type("text", "plain") && description.is_none() {
+            description = Some(part);
+        } else {
+            return Err(format!("Unexpected part in Autocrypt Setup Message: {:?}", part.content_type()));
         }
+    }
+    if description.is_some_and(|d| d.contents().len() > MAX_AUTOCRYPT_SETUP_DESCRIPTION_SIZE) {
+        return Err("Autocrypt Setup Message description is too large".to_string());
+    }
 
-        if !part.is_content_type("text", "plain") {
-            eprintln!("REJECT: securejoin part is not text/plain: {:?}", part.content_type());
-            return false;
+    let setup = setup.ok_or("Autocrypt Setup Message has no application/autocrypt-setup part")?;
+    if setup.contents().len() > MAX_AUTOCRYPT_SETUP_SIZE {
+        return Err(format!("Autocrypt setup attachment of {} bytes is too large", setup.contents().len()));
+    }
+    let text = std::str::from_utf8(setup.contents()).map_err(|_| "Autocrypt setup attachment is not valid UTF-8")?;
+    // The armored key may be wrapped in an HTML document for display, but not in much else.
+    let armor_start = text.find(ARMOR_BEGIN).unwrap_or(0);
+    let armor_end = text.find(ARMOR_END).map_or(text.len(), |i| i + ARMOR_END.len());
+    if text.len() - armor_end.saturating_sub(armor_start) > MAX_AUTOCRYPT_SETUP_DESCRIPTION_SIZE {
+        return Err("Autocrypt setup attachment has too much text around the armor".to_string());
+    }
+
+    let mut setup_policy = policy.clone();
+    setup_policy.allow_skesk_only = true;
+    setup_policy.min_session_keys = setup_policy.min_session_keys.max(1);
+    setup_policy.armor.headers_outgoing = AUTOCRYPT_SETUP_ARMOR_HEADERS.iter().map(|h| h.to_string()).collect();
+
+    let armor = armor::parse(text, "PGP MESSAGE", &setup_policy.armor.headers_outgoing, &setup_policy.armor)
+        .map_err(|e| format!("Autocrypt setup attachment: {}", e))?;
+    if !armor.headers.iter().any(|(key, _)| key.eq_ignore_ascii_case("Passphrase-Format")) {
+        return Err("Autocrypt setup attachment has no Passphrase-Format armor header".to_string());
+    }
+    let summary = check_armor_body(armor.body, &setup_policy, deadline)?;
+    if !summary.pkesk_recipients.is_empty() {
+        return Err("Autocrypt setup attachment is encrypted to public keys".to_string());
+    }
+    Ok(())
+}
+
+/// Checks that a message claiming to be from a mailer-daemon is a well-formed
+/// delivery status notification (RFC 3464, RFC 6522): sent with an empty
+/// envelope sender as `multipart/report; report-type=delivery-status` with a
+/// text notification, optionally as `multipart/alternative`, a
+/// `message/delivery-status` part and optionally the returned message or its
+/// headers. The notification may be at most
+/// `max_text_size` and the returned content `max_returned_size` bytes.
+pub fn check_bounce(
+    message: &Message,
+    mail_from: &str,
+    max_text_size: usize,
+    max_returned_size: usize,
+) -> Result<(), String> {
+    if !mail_from.is_empty() {
+        return Err(format!("Bounce has envelope sender <{}>", mail_from));
+    }
+    if !message.is_content_type("multipart", "report") {
+        return Err("Bounce is not multipart/report".to_string());
+    }
+    let report_type = message.content_type().and_then(|ct| ct.attribute("report-type"));
+    if !report_type.is_some_and(|t| t.eq_ignore_ascii_case("delivery-status")) {
+        return Err(format!("Bounce report-type is not delivery-status: {:?}", report_type));
+    }
+
+    let PartType::Multipart(children) = &message.root_part().body else {
+        return Err("multipart/report has no parts".to_string());
+    };
+    let parts = children
+        .iter()
+        .map(|&id| message.part(id).ok_or("Missing part in bounce"))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (notification, status, returned) = match parts[..] {
+        [notification, status] => (notification, status, None),
+        [notification, status, returned] => (notification, status, Some(returned)),
+        _ => return Err(format!("Bounce has {} parts instead of 2 or 3", parts.len())),
+    };
+
+    let notification_size = bounce_notification_size(message, notification)?;
+    if notification_size > max_text_size {
+        return Err(format!("Bounce notification of {} bytes is too large", notification_size));
+    }
+
+    if !status.is_content_type("message", "delivery-status") {
+        return Err(format!("Bounce part 2 is not message/delivery-status: {:?}", status.content_type()));
+    }
+    let fields = std::str::from_utf8(status.contents()).map_err(|_| "Delivery status is not valid UTF-8")?;
+    check_delivery_status(fields)?;
+
+    if let Some(returned) = returned {
+        if !returned.is_content_type("message", "rfc822") && !returned.is_content_type("text", "rfc822-headers") {
+            return Err(format!("Bounce returned content has type {:?}", returned.content_type()));
+        }
+        let size = returned.raw_end_offset().saturating_sub(returned.raw_body_offset()) as usize;
+        if size > max_returned_size {
+            return Err(format!("Bounce returned content of {} bytes is too large", size));
         }
+    }
+    Ok(())
+}
+
+/// Returns the size of the human-readable part of a bounce: a text part, or a
+/// `multipart/alternative` with a `text/plain` and a `text/html` version.
+fn bounce_notification_size(message: &Message, notification: &MessagePart) -> Result<usize, String> {
+    if !notification.is_content_type("multipart", "alternative") {
+        if !matches!(notification.body, PartType::Text(_))
+            || notification.content_type().is_some_and(|ct| !ct.c_type.eq_ignore_ascii_case("text"))
+        {
+            return Err(format!("Bounce notification is not text: {:?}", notification.content_type()));
+        }
+        return Ok(notification.contents().len());
+    }
+
+    let PartType::Multipart(children) = &notification.body else {
+        return Err("Bounce notification multipart/alternative has no parts".to_string());
+    };
+    let parts = children
+        .iter()
+        .map(|&id| message.part(id).ok_or("Missing part in bounce notification"))
+        .collect::<Result<Vec<_>, _>>()?;
+    let [plain, html] = parts[..] else {
+        return Err(format!("Bounce notification has {} alternatives instead of 2", parts.len()));
+    };
+    if !matches!(plain.body, PartType::Text(_)) || !plain.is_content_type("text", "plain") {
+        return Err(format!("Bounce notification alternative 1 is not text/plain: {:?}", plain.content_type()));
+    }
+    if !matches!(html.body, PartType::Html(_)) || !html.is_content_type("text", "html") {
+        return Err(format!("Bounce notification alternative 2 is not text/html: {:?}", html.content_type()));
+    }
+    Ok(plain.contents().len() + html.contents().len())
+}
 
-        if let PartType::Text(text) = &part.body {
-            let payload = text.trim().to_lowercase();
-            if payload != "secure-join: vc-request" && payload != "secure-join: vg-request" {
-                eprintln!("REJECT: securejoin invalid payload: {}", payload);
-                return false;
+/// Checks the fields of a `message/delivery-status` body (RFC 3464 section 2):
+/// a per-message block with `Reporting-MTA`, followed by one or more
+/// per-recipient blocks with `Final-Recipient`, `Action` and `Status`.
+fn check_delivery_status(text: &str) -> Result<(), String> {
+    let mut blocks: Vec<Vec<&str>> = Vec::new();
+    let mut block: Vec<&str> = Vec::new();
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            if !block.is_empty() {
+                blocks.push(std::mem::take(&mut block));
             }
+        } else if line.starts_with([' ', '\t']) {
+            if block.is_empty() {
+                return Err("Delivery status starts with a continuation line".to_string());
+            }
+        } else {
+            let (name, _) = line.split_once(':').ok_or_else(|| format!("Malformed delivery status field: {:.80}", line))?;
+            block.push(name.trim());
         }
     }
-    parts_count == 1
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+
+    let field = |block: &[&str], name: &str| block.iter().any(|f| f.eq_ignore_ascii_case(name));
+    let field_value = |name: &'static str| {
+        text.lines()
+            .filter_map(|l| l.split_once(':'))
+            .filter(move |(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim())
+    };
+
+    let (per_message, per_recipient) = blocks.split_first().ok_or("Delivery status is empty")?;
+    if !field(per_message, "Reporting-MTA") {
+        return Err("Delivery status has no Reporting-MTA".to_string());
+    }
+    if per_recipient.is_empty() {
+        return Err("Delivery status has no recipients".to_string());
+    }
+    for name in ["Final-Recipient", "Action", "Status"] {
+        if !per_recipient.iter().all(|b| field(b, name)) {
+            return Err(format!("Delivery status recipient without {}", name));
+        }
+    }
+    if let Some(action) = field_value("Action")
+        .find(|a| !["failed", "delayed", "delivered", "relayed", "expanded"].iter().any(|v| a.eq_ignore_ascii_case(v)))
+    {
+        return Err(format!("Invalid delivery status action: {:.80}", action));
+    }
+    if let Some(status) = field_value("Status").find(|s| !is_status_code(s.split_whitespace().next().unwrap_or_default())) {
+        return Err(format!("Invalid delivery status code: {:.80}", status));
+    }
+    Ok(())
 }
 
-pub fn check_encrypted(message: &Message, outgoing: bool) -> bool {
-    if !message.is_content_type("multipart", "encrypted") {
-        return false;
+/// Whether `s` is an RFC 3463 status code such as `5.1.1`.
+fn is_status_code(s: &str) -> bool {
+    let mut parts = s.split('.');
+    let class = parts.next().unwrap_or_default();
+    let rest: Vec<&str> = parts.collect();
+    matches!(class, "2" | "4" | "5")
+        && rest.len() == 2
+        && rest.iter().all(|p| (1..=3).contains(&p.len()) && p.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Checks the originator headers of an outgoing message against the envelope
+/// sender. `From` must be a single mailbox equal to `mail_from`, a `Sender`
+/// must also be `mail_from`, and no display name of theirs may show an
+/// address other than its own. `Reply-To` may point anywhere, but its display
+/// names may not claim a different address on one of `sender_domains`.
+///
+/// Returns the SMTP reply to reject the message with.
+pub fn check_originator_headers(
+    message: &Message,
+    mail_from: &str,
+    sender_domains: &[String],
+) -> Result<(), String> {
+    match message.header_values(HeaderName::From).count() {
+        0 => return Err(MISSING_FROM_550.to_string()),
+        1 => {}
+        _ => return Err(MULTIPLE_FROM_550.to_string()),
+    }
+    let from = match message.from() {
+        Some(Address::List(list)) if list.len() == 1 => &list[0],
+        _ => return Err(FROM_NOT_SINGLE_MAILBOX_550.to_string()),
+    };
+    let from_addr = from.address.as_deref().ok_or(FROM_NOT_SINGLE_MAILBOX_550)?;
+    if !from_addr.eq_ignore_ascii_case(mail_from) {
+        return Err(format!("500 Invalid FROM <{}> for <{}>", from_addr, mail_from));
+    }
+    if has_spoofed_display_name(from) {
+        return Err(SPOOFED_DISPLAY_NAME_550.to_string());
     }
 
-    let mut parts = message.parts.iter().filter(|p| {
-        p.content_type().map_or(true, |ct| ct.c_type.to_lowercase() != "multipart")
-    });
-    
-    // Part 0: application/pgp-encrypted
-    let part0 = match parts.next() {
-        Some(p) => p,
-        None => {
-            eprintln!("REJECT: Missing part 0 in encrypted mail");
-            return false;
-        },
+    if let Some(sender) = message.sender() {
+        let sender = match sender {
+            Address::List(list) if list.len() == 1 => &list[0],
+            _ => return Err(SENDER_MISMATCH_550.to_string()),
+        };
+        if !sender.address.as_deref().is_some_and(|a| a.eq_ignore_ascii_case(mail_from)) {
+            return Err(SENDER_MISMATCH_550.to_string());
+        }
+        if has_spoofed_display_name(sender) {
+            return Err(SPOOFED_DISPLAY_NAME_550.to_string());
+        }
+    }
+
+    if let Some(reply_to) = message.reply_to() {
+        let is_local = |a: &str| {
+            a.rsplit_once('@').is_some_and(|(_, d)| sender_domains.iter().any(|sd| sd.eq_ignore_ascii_case(d)))
+        };
+        if reply_to.iter().any(|addr| display_name_addresses(addr).any(is_local)) {
+            return Err(REPLY_TO_SPOOFED_550.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Whether the display name of `addr` shows an address other than the real
+/// one, as in `"boss@example.org" <mallory@example.org>`.
+fn has_spoofed_display_name(addr: &Addr) -> bool {
+    display_name_addresses(addr).next().is_some()
+}
+
+/// The addresses shown in the display name of `addr` other than its real one.
+fn display_name_addresses<'a>(addr: &'a Addr) -> impl Iterator<Item = &'a str> {
+    let address = addr.address.as_deref().unwrap_or_default();
+    addr.name
+        .as_deref()
+        .unwrap_or_default()
+        .split(|c: char| c.is_whitespace() || "<>()\"',;:[]".contains(c))
+        .filter(move |word| word.contains('@') && !word.eq_ignore_ascii_case(address))
+}
+
+/// Content-Transfer-Encodings permitted on the parts of an outgoing PGP/MIME
+/// message. The control part and the armored data are 7-bit clean, so
+/// RFC 3156 leaves no reason to encode them.
+const PGP_MIME_TRANSFER_ENCODINGS: &[&str] = &["7bit", "8bit"];
+
+/// Content-Transfer-Encodings permitted on the parts of an incoming PGP/MIME
+/// message. Relays on the way may re-encode parts, and the checks below see
+/// the decoded contents either way.
+const PGP_MIME_TRANSFER_ENCODINGS_INCOMING: &[&str] = &["7bit", "8bit", "base64", "quoted-printable"];
+
+/// Checks the RFC 3156 structure of the PGP/MIME subtree rooted at `root` and
+/// returns its control and data parts.
+fn pgp_mime_parts<'a, 'x>(
+    message: &'a Message<'x>,
+    root: &'a MessagePart<'x>,
+    outgoing: bool,
+) -> Result<(&'a MessagePart<'x>, &'a MessagePart<'x>), String> {
+    if !root.is_content_type("multipart", "encrypted") {
+        return Err("Not multipart/encrypted".to_string());
+    }
+    let protocol = root.content_type().and_then(|ct| ct.attribute("protocol"));
+    if !protocol.is_some_and(|p| p.eq_ignore_ascii_case("application/pgp-encrypted")) {
+        return Err(format!("multipart/encrypted protocol is not application/pgp-encrypted: {:?}", protocol));
+    }
+
+    // Exactly two direct children, neither of them nested multiparts or messages.
+    let PartType::Multipart(children) = &root.body else {
+        return Err("multipart/encrypted has no parts".to_string());
     };
-    if !part0.is_content_type("application", "pgp-encrypted") {
-        eprintln!("REJECT: Part 0 is not application/pgp-encrypted: {:?}", part0.content_type());
-        return false;
+    if children.len() != 2 {
+        return Err(format!("multipart/encrypted has {} parts instead of 2", children.len()));
     }
-    if let PartType::Text(text) = &part0.body {
-        if text.trim() != "Version: 1" {
-            return false;
+    let mut parts = children.iter().map(|&id| message.part(id).ok_or("Missing part in encrypted mail"));
+    let part0 = parts.next().unwrap()?;
+    let part1 = parts.next().unwrap()?;
+    let encodings = if outgoing { PGP_MIME_TRANSFER_ENCODINGS } else { PGP_MIME_TRANSFER_ENCODINGS_INCOMING };
+    for (i, part) in [part0, part1].into_iter().enumerate() {
+        if matches!(part.body, PartType::Multipart(_) | PartType::Message(_)) {
+            return Err(format!("Part {} is nested: {:?}", i, part.content_type()));
+        }
+        if let Some(cte) = part.content_transfer_encoding()
+            && !encodings.iter().any(|e| e.eq_ignore_ascii_case(cte))
+        {
+            return Err(format!("Part {} has transfer encoding {}", i, cte));
         }
     }
 
+    // Part 0: application/pgp-encrypted
+    if !part0.is_content_type("application", "pgp-encrypted") {
+        return Err(format!("Part 0 is not application/pgp-encrypted: {:?}", part0.content_type()));
+    }
+    if std::str::from_utf8(part0.contents()).map(str::trim) != Ok("Version: 1") {
+        return Err("Part 0 is not 'Version: 1'".to_string());
+    }
+
     // Part 1: application/octet-stream
-    let part1 = match parts.next() {
-        Some(p) => p,
-        None => {
-            eprintln!("REJECT: Missing part 1 in encrypted mail");
-            return false;
-        },
-    };
     if !part1.is_content_type("application", "octet-stream") {
-        eprintln!("REJECT: Part 1 is not application/octet-stream: {:?}", part1.content_type());
+        return Err(format!("Part 1 is not application/octet-stream: {:?}", part1.content_type()));
+    }
+
+    Ok((part0, part1))
+}
+
+pub fn check_encrypted(
+    message: &Message,
+    outgoing: bool,
+    policy: &OpenPgpPolicy,
+    deadline: Deadline,
+) -> Result<PacketSummary, String> {
+    check_encrypted_part(message, message.root_part(), outgoing, policy, deadline)
+}
+
+/// Validates the PGP/MIME subtree rooted at `root`.
+fn check_encrypted_part(
+    message: &Message,
+    root: &MessagePart,
+    outgoing: bool,
+    policy: &OpenPgpPolicy,
+    deadline: Deadline,
+) -> Result<PacketSummary, String> {
+    let (_, part1) = pgp_mime_parts(message, root, outgoing)?;
+
+    let text = match &part1.body {
+        PartType::Text(text) => text.as_ref(),
+        PartType::Binary(bin) => std::str::from_utf8(bin).map_err(|_| "Part 1 is binary and not valid UTF-8")?,
+        _ => return Err("Part 1 is not text or binary".to_string()),
+    };
+    // Text around the armor would reach the recipient unencrypted.
+    if !is_sole_armored_message(text) {
+        return Err("Part 1 is not a single armored message".to_string());
+    }
+    check_armored_payload(text, outgoing, policy, deadline)
+}
+
+/// Whether the message is a `multipart/mixed` with a `multipart/encrypted`
+/// child, the shape [`check_list_wrapped`] is meant for.
+pub fn has_wrapped_encrypted_part(message: &Message) -> bool {
+    let PartType::Multipart(children) = &message.root_part().body else {
         return false;
+    };
+    message.is_content_type("multipart", "mixed")
+        && children
+            .iter()
+            .filter_map(|&id| message.part(id))
+            .any(|part| part.is_content_type("multipart", "encrypted"))
+}
+
+/// Checks for encrypted mail wrapped by a mailing list: a `multipart/mixed`
+/// holding one valid PGP/MIME subtree with at most one `text/plain` part,
+/// such as a list header, before it and one, such as a list footer, after it.
+/// The text parts may hold at most `max_text_size` bytes together.
+pub fn check_list_wrapped(
+    message: &Message,
+    outgoing: bool,
+    policy: &OpenPgpPolicy,
+    max_text_size: usize,
+    deadline: Deadline,
+) -> Result<PacketSummary, String> {
+    if !message.is_content_type("multipart", "mixed") {
+        return Err("Not multipart/mixed".to_string());
     }
-    
-    if let PartType::Text(text) = &part1.body {
-        if !check_armored_payload(text, outgoing) {
-            return false;
+    let PartType::Multipart(children) = &message.root_part().body else {
+        return Err("multipart/mixed has no parts".to_string());
+    };
+    let parts = children
+        .iter()
+        .map(|&id| message.part(id).ok_or("Missing part in wrapped mail"))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let encrypted = parts
+        .iter()
+        .position(|part| part.is_content_type("multipart", "encrypted"))
+        .ok_or("Wrapped mail has no multipart/encrypted part")?;
+    let (before, after) = (&parts[..encrypted], &parts[encrypted + 1..]);
+    if before.len() > 1 || after.len() > 1 {
+        return Err(format!("Wrapped mail has {} parts besides the encrypted part", parts.len() - 1));
+    }
+
+    let mut text_size = 0;
+    for part in before.iter().chain(after) {
+        let is_text_plain = matches!(part.body, PartType::Text(_))
+            && (part.content_type().is_none() || part.is_content_type("text", "plain"));
+        if !is_text_plain {
+            return Err(format!("Unexpected part in wrapped mail: {:?}", part.content_type()));
+        }
+        text_size += part.contents().len();
+    }
+    if text_size > max_text_size {
+        return Err(format!("Wrapper text parts of {} bytes are too large", text_size));
+    }
+
+    check_encrypted_part(message, parts[encrypted], outgoing, policy, deadline)
+}
+
+/// Whether the message consists of a single `text/plain` part. A missing
+/// Content-Type defaults to `text/plain`.
+pub fn is_single_text_plain(message: &Message) -> bool {
+    message.parts.len() == 1 && (message.content_type().is_none() || message.is_content_type("text", "plain"))
+}
+
+/// Whether the message is a single `text/plain` part holding an armored
+/// OpenPGP message, the shape [`check_inline_pgp`] is meant for.
+pub fn has_inline_armor(message: &Message) -> bool {
+    is_single_text_plain(message) && message.body_text(0).is_some_and(|text| text.contains(ARMOR_BEGIN))
+}
+
+/// Checks for an inline PGP message as sent by legacy clients: a single
+/// `text/plain` body that, apart from surrounding whitespace, is exactly one
+/// armored OpenPGP message.
+pub fn check_inline_pgp(
+    message: &Message,
+    outgoing: bool,
+    policy: &OpenPgpPolicy,
+    deadline: Deadline,
+) -> Result<PacketSummary, String> {
+    if !is_single_text_plain(message) {
+        return Err("Not a single text/plain part".to_string());
+    }
+    let text = message.body_text(0).ok_or("Inline PGP body is not text")?;
+    if !is_sole_armored_message(&text) {
+        return Err("Inline PGP body is not a single armored message".to_string());
+    }
+    check_armored_payload(text.trim(), outgoing, policy, deadline)
+}
+
+/// Whether the message body is `application/pkcs7-mime`, including the
+/// legacy `x-` spelling.
+pub fn is_pkcs7_mime(message: &Message) -> bool {
+    message.is_content_type("application", "pkcs7-mime") || message.is_content_type("application", "x-pkcs7-mime")
+}
+
+/// Checks for an S/MIME encrypted message (RFC 8551): a single
+/// `application/pkcs7-mime; smime-type=enveloped-data` body holding a
+/// structurally valid CMS `EnvelopedData`.
+pub fn check_smime(message: &Message, deadline: Deadline) -> Result<(), String> {
+    if !is_pkcs7_mime(message) {
+        return Err("Not application/pkcs7-mime".to_string());
+    }
+    let ct = message.content_type().ok_or("No Content-Type")?;
+    if !ct.attribute("smime-type").is_some_and(|t| t.eq_ignore_ascii_case("enveloped-data")) {
+        return Err(format!("S/MIME smime-type is not enveloped-data: {:?}", ct.attribute("smime-type")));
+    }
+
+    let body = message.root_part();
+    if !matches!(body.body, PartType::Binary(_) | PartType::InlineBinary(_)) {
+        return Err("S/MIME body is not binary".to_string());
+    }
+    smime::check_enveloped_data(body.contents(), deadline).map_err(|e| format!("S/MIME payload: {}", e))
+}
+
+/// Canonical armor to put in place of a byte range of the raw message.
+pub struct ArmorRewrite {
+    pub range: Range<usize>,
+    pub armor: String,
+}
+
+/// Prepares canonical armor for an outgoing encrypted message that fails the
+/// strict outgoing armor rules but would be accepted as incoming mail.
+///
+/// Only the armor headers are parsed up front, so a message whose headers pass
+/// the outgoing rules is left to the regular check without being decoded here.
+/// The OpenPGP packets are re-encoded unchanged. Returns `None` when the message
+/// is already canonical or cannot be normalized, e.g. because the encrypted part
+/// uses a transfer encoding.
+pub fn normalize_outgoing_armor(
+    message: &Message,
+    policy: &OpenPgpPolicy,
+    deadline: Deadline,
+) -> Option<ArmorRewrite> {
+    // Transfer-encoded parts fail the structural check, so the raw body is the armor.
+    let (_, part1) = pgp_mime_parts(message, message.root_part(), true).ok()?;
+    let text = match &part1.body {
+        PartType::Text(text) => text.as_ref(),
+        PartType::Binary(bin) => std::str::from_utf8(bin).ok()?,
+        _ => return None,
+    };
+    if !is_sole_armored_message(text) {
+        return None;
+    }
+    let outgoing_headers = policy.armor.allowed_headers(true);
+    if !matches!(
+        armor::parse(text, "PGP MESSAGE", outgoing_headers, &policy.armor),
+        Err(ArmorError::HeaderNotAllowed(_))
+    ) {
+        return None;
+    }
+
+    let allowed_headers = policy.armor.allowed_headers(false);
+    let parsed = armor::parse(text, "PGP MESSAGE", allowed_headers, &policy.armor).ok()?;
+    let decoded = armor::decode_body(parsed.body, &policy.armor, deadline).ok()?;
+    check_openpgp_payload(&decoded, policy, deadline).ok()?;
+
+    // Locate the armored block in the raw part body.
+    let body_start = part1.offset_body as usize;
+    let raw = message.raw_message().get(body_start..part1.offset_end as usize)?;
+    let begin = b"-----BEGIN PGP MESSAGE-----";
+    let end = b"-----END PGP MESSAGE-----";
+    let start = raw.windows(begin.len()).position(|w| w == begin)?;
+    let end = raw.windows(end.len()).position(|w| w == end)? + end.len();
+
+    Some(ArmorRewrite {
+        range: body_start + start..body_start + end,
+        armor: armor::encode("PGP MESSAGE", &decoded),
+    })
+}
+
+/// Checks that the number of PKESK packets is plausible for the envelope recipients.
+///
+/// Every recipient other than the sender needs at least one key, and the sender
+/// usually encrypts to itself as well. Up to `max_extra` further keys are tolerated.
+/// Password-only messages carry no PKESK packets at all and are left to
+/// [`OpenPgpPolicy::allow_skesk_only`].
+pub fn check_pkesk_count(
+    summary: &PacketSummary,
+    mail_from: &str,
+    rcpt_tos: &[String],
+    max_extra: usize,
+) -> Result<(), String> {
+    let mail_from = mail_from.to_lowercase();
+    let mut others: Vec<String> = rcpt_tos
+        .iter()
+        .map(|r| r.to_lowercase())
+        .filter(|r| *r != mail_from)
+        .collect();
+    others.sort();
+    others.dedup();
+
+    let pkesk_count = summary.pkesk_recipients.len();
+    if pkesk_count == 0 && summary.skesk_count > 0 {
+        return Ok(());
+    }
+    let min = others.len().max(1);
+    let max = others.len() + 1 + max_extra;
+    if pkesk_count < min || pkesk_count > max {
+        return Err(format!("{} PKESK packets for {} other recipients", pkesk_count, others.len()));
+    }
+    Ok(())
+}
+
+/// Limits on the MIME structure of a message, checked before any filter
+/// walks its parts.
+#[derive(Debug, Clone)]
+pub struct MimeLimits {
+    /// Maximum nesting of multiparts and attached messages.
+    pub max_depth: usize,
+    /// Maximum number of parts, including those of attached messages.
+    pub max_parts: usize,
+    /// Maximum number of header fields of a single part.
+    pub max_headers: usize,
+    /// Maximum total size of all decoded leaf parts.
+    pub max_decoded_size: usize,
+}
+
+/// Checks the structure limits of a raw message before it is parsed, so a
+/// message that would be expensive to parse is refused cheaply.
+///
+/// Counts header fields per header block, boundary delimiters of the
+/// multiparts declared so far and attached messages, without decoding
+/// anything. [`check_mime_limits`] still checks the parsed message.
+pub fn prescan_mime_limits(raw: &[u8], limits: &MimeLimits) -> Result<(), String> {
+    // Boundaries of the enclosing multiparts, with the depth of their parts.
+    let mut boundaries: Vec<(Vec<u8>, usize)> = Vec::new();
+    let mut depth = 0;
+    let mut parts = 1;
+    let mut in_headers = true;
+    let mut headers = 0;
+    let mut content_type = Vec::new();
+    let mut in_content_type = false;
+
+    for line in raw.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        if let Some(rest) = line.strip_prefix(b"--")
+            && let Some(i) = boundaries.iter().rposition(|(b, _)| rest.starts_with(b))
+        {
+            let closing = rest[boundaries[i].0.len()..].starts_with(b"--");
+            depth = boundaries[i].1;
+            boundaries.truncate(if closing { i } else { i + 1 });
+            if !closing {
+                parts += 1;
+                if parts > limits.max_parts {
+                    return Err(format!("more than {} MIME parts", limits.max_parts));
+                }
+                (in_headers, headers, in_content_type) = (true, 0, false);
+                content_type.clear();
+            } else {
+                in_headers = false;
+            }
+            continue;
+        }
+        if !in_headers {
+            continue;
         }
-    } else if let PartType::Binary(bin) = &part1.body {
-        if let Ok(text) = std::str::from_utf8(bin) {
-            if !check_armored_payload(text, outgoing) {
-                return false;
+
+        if line.is_empty() {
+            let value = std::mem::take(&mut content_type);
+            let value = value.trim_ascii_start();
+            let lowercase = value.to_ascii_lowercase();
+            (headers, in_content_type) = (0, false);
+            if lowercase.starts_with(b"multipart/") {
+                in_headers = false;
+                if let Some(boundary) = boundary_param(value) {
+                    if depth + 1 > limits.max_depth {
+                        return Err(format!("MIME nesting deeper than {}", limits.max_depth));
+                    }
+                    boundaries.push((boundary, depth + 1));
+                }
+            } else if lowercase.starts_with(b"message/rfc822") || lowercase.starts_with(b"message/global") {
+                // The body of an attached message starts with its own header block.
+                depth += 1;
+                parts += 1;
+                if depth > limits.max_depth {
+                    return Err(format!("MIME nesting deeper than {}", limits.max_depth));
+                }
+                if parts > limits.max_parts {
+                    return Err(format!("more than {} MIME parts", limits.max_parts));
+                }
+            } else {
+                in_headers = false;
+            }
+        } else if line.starts_with(b" ") || line.starts_with(b"\t") {
+            if in_content_type {
+                content_type.extend_from_slice(line);
             }
         } else {
-            eprintln!("REJECT: Part 1 is binary and not valid UTF-8");
-            return false;
+            headers += 1;
+            if headers > limits.max_headers {
+                return Err(format!("part with more than {} header fields", limits.max_headers));
+            }
+            let (name, value) = line.split_at(line.iter().position(|&b| b == b':').unwrap_or(line.len()));
+            in_content_type = name.trim_ascii().eq_ignore_ascii_case(b"content-type");
+            if in_content_type {
+                content_type = value.get(1..).unwrap_or_default().to_vec();
+            }
         }
-    } else {
-        eprintln!("REJECT: Part 1 is not text or binary");
-        return false;
     }
+    Ok(())
+}
 
-    // Should have exactly 2 parts
-    if let Some(extra) = parts.next() {
-        eprintln!("REJECT: Encrypted mail has more than 2 parts. Extra part type: {:?}", extra.content_type());
-        return false;
+/// Extracts the `boundary` parameter of a raw Content-Type value.
+fn boundary_param(content_type: &[u8]) -> Option<Vec<u8>> {
+    const NAME: &[u8] = b"boundary=";
+    let start = content_type.windows(NAME.len()).position(|w| w.eq_ignore_ascii_case(NAME))?;
+    let rest = &content_type[start + NAME.len()..];
+    let boundary = match rest.strip_prefix(b"\"") {
+        Some(quoted) => quoted.split(|&b| b == b'"').next()?,
+        None => rest.split(|&b| matches!(b, b';' | b' ' | b'\t')).next()?,
+    };
+    (!boundary.is_empty()).then(|| boundary.to_vec())
+}
+
+pub fn check_mime_limits(message: &Message, limits: &MimeLimits, deadline: Deadline) -> Result<(), String> {
+    let mut parts = 0;
+    let mut decoded_size = 0;
+    // Parts still to visit, with their nesting depth.
+    let mut stack = vec![(message, message.root_part(), 0)];
+    while let Some((message, part, depth)) = stack.pop() {
+        deadline.check()?;
+        parts += 1;
+        if parts > limits.max_parts {
+            return Err(format!("more than {} MIME parts", limits.max_parts));
+        }
+        if depth > limits.max_depth {
+            return Err(format!("MIME nesting deeper than {}", limits.max_depth));
+        }
+        if part.headers.len() > limits.max_headers {
+            return Err(format!("part with {} header fields", part.headers.len()));
+        }
+        match &part.body {
+            PartType::Multipart(children) => {
+                for &id in children {
+                    let child = message.part(id).ok_or("missing MIME part")?;
+                    stack.push((message, child, depth + 1));
+                }
+            }
+            PartType::Message(nested) => stack.push((nested, nested.root_part(), depth + 1)),
+            _ => {
+                decoded_size += part.contents().len();
+                if decoded_size > limits.max_decoded_size {
+                    return Err(format!("more than {} bytes of decoded content", limits.max_decoded_size));
+                }
+            }
+        }
     }
+    Ok(())
+}
 
-    true
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mail_parser::MessageParser;
+    use std::time::Duration;
+
+    const KEY_ID: [u8; 8] = [0x69, 0xF4, 0xD9, 0x91, 0xCB, 0x00, 0x30, 0x45];
+
+    /// A v3 PKESK followed by a v1 SEIPD whose body is sent as one 512-octet
+    /// partial chunk and an empty final chunk.
+    fn partial_seipd_message() -> Vec<u8> {
+        let mut data = vec![0xC1, 10, 3];
+        data.extend_from_slice(&KEY_ID);
+        data.push(1);
+        data.extend_from_slice(&[0xD2, 0xE9, 1]);
+        data.extend_from_slice(&[0xAA; 511]);
+        data.push(0);
+        data
+    }
+
+    #[test]
+    fn accepts_partial_seipd_with_empty_final_chunk() {
+        let policy = OpenPgpPolicy::default();
+        let data = partial_seipd_message();
+        let expected = PacketSummary { pkesk_recipients: vec![RecipientKey::KeyId(KEY_ID)], skesk_count: 0 };
+
+        assert_eq!(check_openpgp_payload(&data, &policy, Deadline::NONE), Ok(expected.clone()));
+        let armored = armor::encode("PGP MESSAGE", &data);
+        assert_eq!(check_armored_payload(&armored, true, &policy, Deadline::NONE), Ok(expected));
+    }
+
+    fn packet(tag: u8, body: &[u8]) -> Vec<u8> {
+        [&[0xC0 | tag, body.len() as u8][..], body].concat()
+    }
+
+    /// Legacy headers only have room for tags below 16.
+    fn legacy_packet(tag: u8, body: &[u8]) -> Vec<u8> {
+        [&[0x80 | tag << 2, body.len() as u8][..], body].concat()
+    }
+
+    /// A named packet sequence and the rejection it should get under a policy, if any.
+    type PacketCase<'a> = (&'a str, &'a OpenPgpPolicy, Vec<&'a [u8]>, Option<PacketReject>);
+
+    fn check_packet_rules(cases: &[PacketCase]) {
+        for (name, policy, packets, expected) in cases {
+            let result = check_openpgp_payload(&packets.concat(), policy, Deadline::NONE).map(|_| ());
+            assert_eq!(result, expected.map_or(Ok(()), Err), "{name}");
+        }
+    }
+
+    #[test]
+    fn packet_policy_rules() {
+        let pkesk3 = packet(PKESK, &[&[3][..], &KEY_ID, &[1]].concat());
+        let skesk4 = packet(SKESK, &[4, 9, 3]);
+        let seipd1 = packet(SEIPD, &[1, 0xAA]);
+        let aead = packet(AEAD_ENCRYPTED, &[1, 9, 1, 6, 0xAA]);
+        let marker = packet(MARKER, b"PGP");
+        let pkesk6 = packet(PKESK, &[&[6, 21, 4][..], &[0x11; 20], &[1]].concat());
+        let skesk6 = packet(SKESK, &[6, 3, 9, 2, 3]);
+        let seipd2 = packet(SEIPD, &[2, 9, 2, 16, 0xAA]);
+        let padding = packet(PADDING, &[0; 8]);
+        let policy = |configure: fn(&mut OpenPgpPolicy)| {
+            let mut policy = OpenPgpPolicy::default();
+            configure(&mut policy);
+            policy
+        };
+        let default = OpenPgpPolicy::default();
+        let min_two = policy(|p| p.min_session_keys = 2);
+        let max_one = policy(|p| p.max_session_keys = Some(1));
+        let no_skesk_only = policy(|p| p.allow_skesk_only = false);
+        let old_format = policy(|p| p.allow_old_format = true);
+        let skip_marker = policy(|p| p.skip_marker = true);
+        let allow_aead = policy(|p| p.allow_aead = true);
+
+        check_packet_rules(&[
+            ("min keys met", &min_two, vec![&pkesk3, &skesk4, &seipd1], None),
+            ("min keys missed", &min_two, vec![&pkesk3, &seipd1], Some(PacketReject::TooFewSessionKeys(1))),
+            ("max keys met", &max_one, vec![&pkesk3, &seipd1], None),
+            ("max keys exceeded", &max_one, vec![&pkesk3, &pkesk3, &seipd1], Some(PacketReject::TooManySessionKeys(2))),
+            ("skesk-only allowed", &default, vec![&skesk4, &seipd1], None),
+            ("skesk with pkesk", &no_skesk_only, vec![&pkesk3, &skesk4, &seipd1], None),
+            ("skesk-only refused", &no_skesk_only, vec![&skesk4, &seipd1], Some(PacketReject::SkeskOnly)),
+            (
+                "old format allowed",
+                &old_format,
+                vec![&legacy_packet(PKESK, &pkesk3[2..]), &seipd1],
+                None,
+            ),
+            (
+                "old format refused",
+                &default,
+                vec![&legacy_packet(PKESK, &pkesk3[2..]), &seipd1],
+                Some(PacketReject::OldFormat),
+            ),
+            ("marker skipped", &skip_marker, vec![&marker, &pkesk3, &seipd1], None),
+            ("marker refused", &default, vec![&marker, &pkesk3, &seipd1], Some(PacketReject::UnexpectedPacket(MARKER))),
+            ("aead allowed", &allow_aead, vec![&pkesk3, &aead], None),
+            ("aead refused", &default, vec![&pkesk3, &aead], Some(PacketReject::AeadNotAllowed)),
+            ("v1 session keys", &default, vec![&pkesk3, &skesk4, &seipd1], None),
+            (
+                "session key versions mixed",
+                &default,
+                vec![&pkesk3, &packet(SKESK, &[6, 9, 3])],
+                Some(PacketReject::VersionMismatch),
+            ),
+            ("no encrypted data", &default, vec![&pkesk3], Some(PacketReject::MissingEncryptedData)),
+            ("empty session key", &default, vec![&packet(PKESK, &[]), &seipd1], Some(PacketReject::EmptyPacket(PKESK))),
+            ("truncated pkesk", &default, vec![&packet(PKESK, &[3, 1, 2]), &seipd1], Some(PacketReject::MalformedPkesk)),
+            ("literal data", &default, vec![&packet(11, b"b\0hi")], Some(PacketReject::UnexpectedPacket(11))),
+            // RFC 9580: v6 session key packets go with SEIPD v2, and Padding only after the encrypted data.
+            ("v6 pkesk", &default, vec![&pkesk6, &seipd2], None),
+            ("anonymous v6 pkesk", &default, vec![&packet(PKESK, &[6, 0, 9]), &seipd2], None),
+            ("v6 skesk", &default, vec![&skesk6, &seipd2], None),
+            ("v6 pkesk and skesk", &default, vec![&pkesk6, &skesk6, &seipd2], None),
+            ("v3 pkesk with seipd v2", &default, vec![&pkesk3, &seipd2], Some(PacketReject::VersionMismatch)),
+            ("v6 pkesk with seipd v1", &default, vec![&pkesk6, &seipd1], Some(PacketReject::VersionMismatch)),
+            ("v3 and v6 pkesk", &default, vec![&pkesk3, &pkesk6, &seipd2], Some(PacketReject::VersionMismatch)),
+            ("v6 pkesk with aead", &allow_aead, vec![&pkesk6, &aead], Some(PacketReject::VersionMismatch)),
+            (
+                "seipd version 3",
+                &default,
+                vec![&pkesk6, &packet(SEIPD, &[3, 0xAA])],
+                Some(PacketReject::UnsupportedVersion { tag: SEIPD, version: 3 }),
+            ),
+            (
+                "pkesk version 5",
+                &default,
+                vec![&packet(PKESK, &[5, 0, 9]), &seipd2],
+                Some(PacketReject::UnsupportedVersion { tag: PKESK, version: 5 }),
+            ),
+            ("padding after data", &default, vec![&pkesk6, &seipd2, &padding, &padding], None),
+            ("padding before data", &default, vec![&pkesk6, &padding, &seipd2], Some(PacketReject::UnexpectedPacket(PADDING))),
+            ("pkesk after data", &default, vec![&pkesk6, &seipd2, &pkesk6], Some(PacketReject::PacketAfterEncryptedData(PKESK))),
+            ("second seipd", &default, vec![&pkesk6, &seipd2, &seipd2], Some(PacketReject::PacketAfterEncryptedData(SEIPD))),
+        ]);
+    }
+
+    #[test]
+    fn pkesk_count_matches_recipients() {
+        let summary = |pkesks: usize, skesk_count: usize| PacketSummary {
+            pkesk_recipients: vec![RecipientKey::KeyId(KEY_ID); pkesks],
+            skesk_count,
+        };
+        let rcpts = |r: &[&str]| r.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        let two = rcpts(&["b@example.org", "C@example.org", "c@example.org"]);
+
+        assert!(check_pkesk_count(&summary(2, 0), "a@example.org", &two, 0).is_ok());
+        assert!(check_pkesk_count(&summary(3, 0), "a@example.org", &two, 0).is_ok());
+        assert!(check_pkesk_count(&summary(1, 0), "a@example.org", &two, 0).is_err());
+        assert!(check_pkesk_count(&summary(4, 0), "a@example.org", &two, 0).is_err());
+        assert!(check_pkesk_count(&summary(4, 0), "a@example.org", &two, 1).is_ok());
+        // Mail to oneself still needs the sender's own key.
+        assert!(check_pkesk_count(&summary(0, 0), "a@example.org", &rcpts(&["A@example.org"]), 0).is_err());
+        assert!(check_pkesk_count(&summary(1, 0), "a@example.org", &rcpts(&["A@example.org"]), 0).is_ok());
+        // Password-only messages have no recipient keys to count.
+        assert!(check_pkesk_count(&summary(0, 1), "a@example.org", &two, 0).is_ok());
+        assert!(check_pkesk_count(&summary(1, 1), "a@example.org", &two, 0).is_err());
+    }
+
+    /// A PGP/MIME entity, starting with its Content-Type header, whose data part holds `payload`.
+    fn pgp_mime_entity(payload: &str) -> String {
+        format!(
+            "Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\"; boundary=\"inner\"\r\n\r\n\
+            --inner\r\nContent-Type: application/pgp-encrypted\r\n\r\nVersion: 1\r\n\
+            --inner\r\nContent-Type: application/octet-stream\r\n\r\n{payload}\r\n--inner--\r\n"
+        )
+    }
+
+    fn armored_message() -> String {
+        armor::encode("PGP MESSAGE", &partial_seipd_message())
+    }
+
+    #[test]
+    fn pgp_mime_data_part_is_only_armor() {
+        let policy = OpenPgpPolicy::default();
+        let check = |payload: &str, outgoing: bool| {
+            let data = format!("From: a@example.org\r\nTo: b@example.org\r\n{}", pgp_mime_entity(payload));
+            let message = MessageParser::default().parse(data.as_bytes()).unwrap();
+            check_encrypted(&message, outgoing, &policy, Deadline::NONE)
+        };
+        let armored = armored_message();
+
+        for outgoing in [false, true] {
+            assert!(check(&armored, outgoing).is_ok());
+            assert!(check(&format!("\r\n \r\n{armored}\r\n\r\n"), outgoing).is_ok());
+            assert!(check(&format!("Meet me at noon.\r\n{armored}"), outgoing).is_err());
+            assert!(check(&format!("{armored}\r\nMeet me at noon."), outgoing).is_err());
+        }
+    }
+
+    /// A PGP/MIME message from `Content-Type` parameters and the two parts,
+    /// each as its headers and body.
+    fn pgp_mime(params: &str, parts: &[(&str, &str)]) -> String {
+        let mut mail = format!(
+            "From: a@example.org\r\nTo: b@example.org\r\nMIME-Version: 1.0\r\n\
+            Content-Type: multipart/encrypted; {params}boundary=\"inner\"\r\n\r\n"
+        );
+        for (headers, body) in parts {
+            mail += &format!("--inner\r\n{headers}\r\n\r\n{body}\r\n");
+        }
+        mail += "--inner--\r\n";
+        mail
+    }
+
+    #[test]
+    fn pgp_mime_structure() {
+        use base64::Engine as _;
+
+        let policy = OpenPgpPolicy::default();
+        let check = |mail: &str, outgoing: bool| {
+            let message = MessageParser::default().parse(mail.as_bytes()).unwrap();
+            check_encrypted(&message, outgoing, &policy, Deadline::NONE).map(|_| ())
+        };
+        let protocol = "protocol=\"application/pgp-encrypted\"; ";
+        let armored = armored_message();
+        let base64 = base64::engine::general_purpose::STANDARD.encode(&armored);
+        let qp = armored.replace('=', "=3D");
+        let control = ("Content-Type: application/pgp-encrypted", "Version: 1");
+        let data = ("Content-Type: application/octet-stream", armored.as_str());
+        let nested = "Content-Type: multipart/mixed; boundary=\"x\"";
+        let encoded = |headers: &str, encoding: &str| format!("{headers}\r\nContent-Transfer-Encoding: {encoding}");
+        let qp_data = encoded(data.0, "quoted-printable");
+        let base64_control = encoded(control.0, "base64");
+        let uuencoded_data = encoded(data.0, "x-uuencode");
+
+        // (name, message, accepted outgoing, accepted incoming)
+        let cases = [
+            ("valid", pgp_mime(protocol, &[control, data]), true, true),
+            ("protocol case", pgp_mime("protocol=\"Application/PGP-Encrypted\"; ", &[control, data]), true, true),
+            ("no protocol", pgp_mime("", &[control, data]), false, false),
+            ("S/MIME", pgp_mime("protocol=\"application/pkcs7-mime\"; ", &[control, data]), false, false),
+            ("version 2", pgp_mime(protocol, &[(control.0, "Version: 2"), data]), false, false),
+            ("empty control", pgp_mime(protocol, &[(control.0, ""), data]), false, false),
+            ("one part", pgp_mime(protocol, &[data]), false, false),
+            ("three parts", pgp_mime(protocol, &[control, data, data]), false, false),
+            ("swapped parts", pgp_mime(protocol, &[data, control]), false, false),
+            ("text data part", pgp_mime(protocol, &[control, ("Content-Type: text/plain", &armored)]), false, false),
+            ("nested data part", pgp_mime(protocol, &[control, (nested, "--x\r\n\r\nhi\r\n--x--")]), false, false),
+            ("7bit", pgp_mime(protocol, &[(&encoded(control.0, "7bit"), control.1), data]), true, true),
+            ("base64 data", pgp_mime(protocol, &[control, (&encoded(data.0, "base64"), &base64)]), false, true),
+            ("quoted-printable data", pgp_mime(protocol, &[control, (&qp_data, &qp)]), false, true),
+            ("base64 control", pgp_mime(protocol, &[(&base64_control, "VmVyc2lvbjogMQ=="), data]), false, true),
+            ("unknown encoding", pgp_mime(protocol, &[control, (&uuencoded_data, &armored)]), false, false),
+        ];
+        for (name, mail, outgoing, incoming) in &cases {
+            assert_eq!(check(mail, true).is_ok(), *outgoing, "{name} outgoing: {:?}", check(mail, true));
+            assert_eq!(check(mail, false).is_ok(), *incoming, "{name} incoming: {:?}", check(mail, false));
+        }
+    }
+
+    #[test]
+    fn normalizes_outgoing_armor_headers() {
+        let policy = OpenPgpPolicy::default();
+        let mail = |payload: &str| {
+            format!(
+                "From: a@example.org\r\nTo: b@example.org\r\nSubject: hi\r\nMIME-Version: 1.0\r\n{}",
+                pgp_mime_entity(payload)
+            )
+        };
+        let normalize = |mail: &str| {
+            let message = MessageParser::default().parse(mail.as_bytes()).unwrap();
+            normalize_outgoing_armor(&message, &policy, Deadline::NONE)
+        };
+        let canonical = armored_message();
+        let with_version = canonical.replacen("-----\r\n\r\n", "-----\r\nVersion: GnuPG v1\r\n\r\n", 1);
+
+        let original = mail(&with_version);
+        let message = MessageParser::default().parse(original.as_bytes()).unwrap();
+        assert!(check_encrypted(&message, true, &policy, Deadline::NONE).is_err());
+        let rewrite = normalize(&original).unwrap();
+        let mut data = original.into_bytes();
+        data.splice(rewrite.range, rewrite.armor.into_bytes());
+        // Only the armor block changes, so the headers and MIME structure stay as they were.
+        assert_eq!(String::from_utf8(data.clone()).unwrap(), mail(&canonical));
+        let message = MessageParser::default().parse(&data).unwrap();
+        assert!(check_encrypted(&message, true, &policy, Deadline::NONE).is_ok());
+
+        // Canonical armor needs no rewrite, and armor the incoming rules refuse as well gets none.
+        assert!(normalize(&mail(&canonical)).is_none());
+        let unknown_header = canonical.replacen("-----\r\n\r\n", "-----\r\nX-Secret: 1\r\n\r\n", 1);
+        assert!(normalize(&mail(&unknown_header)).is_none());
+    }
+
+    #[test]
+    fn fallbacks_apply_only_to_their_shape() {
+        let parse = |data: &str| MessageParser::default().parse(data.as_bytes()).unwrap().into_owned();
+        let plain = parse("Subject: hi\r\n\r\nMeet me at noon.\r\n");
+        let inline = parse(&format!("Subject: hi\r\n\r\n{}\r\n", armored_message()));
+        let wrapped = parse(std::str::from_utf8(&list_wrapped(&[], &[("text/plain", "List footer")])).unwrap());
+        let mixed = parse(
+            "Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n--b\r\n\r\nhi\r\n--b\r\n\
+            Content-Type: application/octet-stream\r\n\r\nxyz\r\n--b--\r\n",
+        );
+
+        assert!(!has_inline_armor(&plain));
+        assert!(has_inline_armor(&inline));
+        assert!(!has_inline_armor(&wrapped));
+        assert!(!has_wrapped_encrypted_part(&plain));
+        assert!(!has_wrapped_encrypted_part(&mixed));
+        assert!(has_wrapped_encrypted_part(&wrapped));
+    }
+
+    /// A `multipart/mixed` list wrapper around a PGP/MIME message with the
+    /// given parts before and after it, each as `(content type, body)`.
+    fn list_wrapped(before: &[(&str, &str)], after: &[(&str, &str)]) -> Vec<u8> {
+        let text_part = |(ct, body): &(&str, &str)| format!("--outer\r\nContent-Type: {ct}\r\n\r\n{body}\r\n");
+        let mut mail = "From: a@example.org\r\nTo: b@example.org\r\nMIME-Version: 1.0\r\n\
+            Content-Type: multipart/mixed; boundary=\"outer\"\r\n\r\n".to_string();
+        before.iter().for_each(|p| mail += &text_part(p));
+        mail += &format!("--outer\r\n{}", pgp_mime_entity(&armored_message()));
+        after.iter().for_each(|p| mail += &text_part(p));
+        mail += "--outer--\r\n";
+        mail.into_bytes()
+    }
+
+    #[test]
+    fn list_wrapped_footers() {
+        let policy = OpenPgpPolicy::default();
+        let check = |before: &[(&str, &str)], after: &[(&str, &str)], max: usize| {
+            let data = list_wrapped(before, after);
+            let message = MessageParser::default().parse(&data).unwrap();
+            check_list_wrapped(&message, false, &policy, max, Deadline::NONE)
+        };
+        let plain = ("text/plain", "List footer");
+
+        assert!(check(&[], &[], 0).is_ok());
+        assert!(check(&[plain], &[plain], 100).is_ok());
+        assert!(check(&[], &[plain], 100).is_ok());
+        // The size limit applies to all text parts together.
+        assert!(check(&[plain], &[plain], 11).is_err());
+        assert!(check(&[], &[plain], 11).is_ok());
+        assert!(check(&[], &[plain, plain], 100).is_err());
+        assert!(check(&[plain, plain], &[], 100).is_err());
+        assert!(check(&[], &[("text/html", "<p>List footer</p>")], 100).is_err());
+        assert!(check(&[], &[("application/octet-stream", "List footer")], 100).is_err());
+    }
+
+    #[test]
+    fn securejoin_key_is_single_public_key() {
+        let check_until = |tags: &[u8], deadline: Deadline| {
+            let key: Vec<u8> = tags.iter().flat_map(|&tag| [0xC0 | tag, 1, 4]).collect();
+            let armored = armor::encode("PGP PUBLIC KEY BLOCK", &key);
+            let data = format!("Content-Type: application/pgp-keys\r\n\r\n{armored}");
+            let message = MessageParser::default().parse(data.as_bytes()).unwrap();
+            check_securejoin_key(message.root_part(), 32 * 1024, deadline)
+        };
+        let check = |tags: &[u8]| check_until(tags, Deadline::NONE);
+
+        assert_eq!(check(&[PUBLIC_KEY]), Ok(()));
+        assert_eq!(check(&[PUBLIC_KEY, USER_ID, SIGNATURE, USER_ATTRIBUTE, SIGNATURE, PUBLIC_SUBKEY, SIGNATURE]), Ok(()));
+        assert_eq!(check(&[PUBLIC_KEY, USER_ID, PUBLIC_KEY]), Err("more than one public key".to_string()));
+        // Secret keys and subkeys, literal data.
+        assert!(check(&[5]).is_err());
+        assert!(check(&[PUBLIC_KEY, 7]).is_err());
+        assert_eq!(check(&[PUBLIC_KEY, 11]), Err("unexpected packet type 11 in public key".to_string()));
+        assert!(check(&[USER_ID, PUBLIC_KEY]).is_err());
+        assert!(check_until(&[PUBLIC_KEY, USER_ID], Deadline::after(Duration::ZERO)).is_err());
+    }
+
+    /// An Autocrypt Setup Message with the given header value and parts, each
+    /// as `(content type, body)`.
+    fn autocrypt_setup(version: &str, parts: &[(&str, &str)]) -> Vec<u8> {
+        let mut mail = format!(
+            "From: a@example.org\r\nTo: a@example.org\r\nAutocrypt-Setup-Message: {version}\r\n\
+            MIME-Version: 1.0\r\nContent-Type: multipart/mixed; boundary=\"setup\"\r\n\r\n"
+        );
+        for (ct, body) in parts {
+            mail += &format!("--setup\r\nContent-Type: {ct}\r\n\r\n{body}\r\n");
+        }
+        mail += "--setup--\r\n";
+        mail.into_bytes()
+    }
+
+    /// A password-encrypted key in armor with the given headers.
+    fn setup_armor(packets: &[&[u8]], headers: &str) -> String {
+        let armored = armor::encode("PGP MESSAGE", &packets.concat());
+        armored.replacen("-----\r\n\r\n", &format!("-----\r\n{headers}\r\n"), 1)
+    }
+
+    #[test]
+    fn autocrypt_setup_messages() {
+        let policy = OpenPgpPolicy::default();
+        let check = |data: &[u8], deadline: Deadline| {
+            let message = MessageParser::default().parse(data).unwrap();
+            check_autocrypt_setup(&message, &policy, deadline)
+        };
+        let pkesk = packet(PKESK, &[&[3][..], &KEY_ID, &[1]].concat());
+        let skesk = packet(SKESK, &[4, 9, 3]);
+        let seipd = packet(SEIPD, &[1, 0xAA]);
+        let passphrase = "Passphrase-Format: numeric9x4\r\nPassphrase-Begin: 12\r\n";
+        let key = setup_armor(&[&skesk, &seipd], passphrase);
+        let description = ("text/plain", "This message contains your Autocrypt setup.");
+        let attachment = ("application/autocrypt-setup", key.as_str());
+
+        assert_eq!(check(&autocrypt_setup("v1", &[description, attachment]), Deadline::NONE), Ok(()));
+        assert_eq!(check(&autocrypt_setup("v1", &[attachment]), Deadline::NONE), Ok(()));
+        let html = format!("<html><body><pre>{key}</pre></body></html>");
+        assert_eq!(check(&autocrypt_setup("v1", &[("application/autocrypt-setup", &html)]), Deadline::NONE), Ok(()));
+
+        assert!(check(&autocrypt_setup("v2", &[description, attachment]), Deadline::NONE).is_err());
+        assert!(check(&autocrypt_setup("v1", &[description]), Deadline::NONE).is_err());
+        let html_part = ("text/html", "<p>hi</p>");
+        assert!(check(&autocrypt_setup("v1", &[description, html_part, attachment]), Deadline::NONE).is_err());
+        assert!(check(&autocrypt_setup("v1", &[description, ("application/octet-stream", &key)]), Deadline::NONE).is_err());
+        assert!(check(&autocrypt_setup("v1", &[attachment, attachment]), Deadline::NONE).is_err());
+
+        let to_key = setup_armor(&[&pkesk, &skesk, &seipd], passphrase);
+        assert_eq!(
+            check(&autocrypt_setup("v1", &[("application/autocrypt-setup", &to_key)]), Deadline::NONE),
+            Err("Autocrypt setup attachment is encrypted to public keys".to_string())
+        );
+        let no_format = setup_armor(&[&skesk, &seipd], "Passphrase-Begin: 12\r\n");
+        assert!(check(&autocrypt_setup("v1", &[("application/autocrypt-setup", &no_format)]), Deadline::NONE).is_err());
+
+        assert!(check(&autocrypt_setup("v1", &[attachment]), Deadline::after(Duration::ZERO)).is_err());
+    }
+
+    const DELIVERY_STATUS: &str = "Reporting-MTA: dns; mx.example.org\r\n\r\n\
+        Final-Recipient: rfc822; b@example.org\r\nAction: failed\r\nStatus: 5.1.1\r\n";
+
+    /// A delivery status notification with the given human-readable part.
+    fn bounce(notification: &str) -> Vec<u8> {
+        format!(
+            "From: MAILER-DAEMON@example.org\r\nTo: a@example.org\r\nAuto-Submitted: auto-replied\r\n\
+            MIME-Version: 1.0\r\nContent-Type: multipart/report; report-type=delivery-status; boundary=\"report\"\r\n\r\n\
+            --report\r\n{notification}\r\n\
+            --report\r\nContent-Type: message/delivery-status\r\n\r\n{DELIVERY_STATUS}\r\n\
+            --report--\r\n"
+        )
+        .into_bytes()
+    }
+
+    fn alternative(plain_type: &str, html_type: &str) -> String {
+        format!(
+            "Content-Type: multipart/alternative; boundary=\"alt\"\r\n\r\n\
+            --alt\r\nContent-Type: {plain_type}\r\n\r\nDelivery failed.\r\n\
+            --alt\r\nContent-Type: {html_type}\r\n\r\n<p>Delivery failed.</p>\r\n\
+            --alt--"
+        )
+    }
+
+    #[test]
+    fn bounce_notifications() {
+        let check = |notification: &str, max: usize| {
+            let data = bounce(notification);
+            let message = MessageParser::default().parse(&data).unwrap();
+            check_bounce(&message, "", max, 1024)
+        };
+
+        assert_eq!(check("Content-Type: text/plain\r\n\r\nDelivery failed.", 100), Ok(()));
+        assert!(check("Content-Type: text/plain\r\n\r\nDelivery failed.", 10).is_err());
+        assert!(check("Content-Type: application/octet-stream\r\n\r\nDelivery failed.", 100).is_err());
+
+        assert_eq!(check(&alternative("text/plain", "text/html"), 100), Ok(()));
+        // The size limit applies to both alternatives together.
+        assert!(check(&alternative("text/plain", "text/html"), 30).is_err());
+        assert!(check(&alternative("text/html", "text/plain"), 100).is_err());
+        assert!(check(&alternative("text/plain", "application/octet-stream"), 100).is_err());
+    }
+
+    #[test]
+    fn delivery_status_fields() {
+        assert_eq!(check_delivery_status(DELIVERY_STATUS), Ok(()));
+        assert_eq!(
+            check_delivery_status("Final-Recipient: rfc822; b@example.org\r\nAction: failed\r\nStatus: 5.1.1\r\n"),
+            Err("Delivery status has no Reporting-MTA".to_string())
+        );
+        assert_eq!(
+            check_delivery_status("Reporting-MTA: dns; mx.example.org\r\n"),
+            Err("Delivery status has no recipients".to_string())
+        );
+        assert!(check_delivery_status(&DELIVERY_STATUS.replace("Status: 5.1.1", "Status: 9.9")).is_err());
+        assert!(check_delivery_status(&DELIVERY_STATUS.replace("failed", "bounced")).is_err());
+        assert!(check_delivery_status(&DELIVERY_STATUS.replace("Action: failed\r\n", "")).is_err());
+        assert!(check_delivery_status(" folded\r\nReporting-MTA: dns; mx.example.org\r\n").is_err());
+    }
+
+    #[test]
+    fn originator_headers() {
+        let domains = ["example.org".to_string(), "example.net".to_string()];
+        let check = |headers: &str| {
+            let data = format!("{headers}\r\nSubject: hi\r\n\r\nhi\r\n");
+            let message = MessageParser::default().parse(data.as_bytes()).unwrap();
+            check_originator_headers(&message, "a@example.org", &domains)
+        };
+
+        assert_eq!(check("From: A <a@example.org>\r\nSender: a@example.org"), Ok(()));
+        assert_eq!(check("Subject: no from"), Err(MISSING_FROM_550.to_string()));
+        assert_eq!(check("From: a@example.org\r\nFrom: a@example.org"), Err(MULTIPLE_FROM_550.to_string()));
+        assert_eq!(check("From: a@example.org, b@example.org"), Err(FROM_NOT_SINGLE_MAILBOX_550.to_string()));
+        assert!(check("From: b@example.org").is_err());
+        assert_eq!(check("From: a@example.org\r\nSender: b@example.org"), Err(SENDER_MISMATCH_550.to_string()));
+        assert_eq!(check("From: \"b@example.org\" <a@example.org>"), Err(SPOOFED_DISPLAY_NAME_550.to_string()));
+
+        // Reply-To may point anywhere, as long as it does not pose as a local address.
+        assert_eq!(check("From: a@example.org\r\nReply-To: list@lists.example.com"), Ok(()));
+        assert_eq!(check("From: a@example.org\r\nReply-To: A <a@example.org>, \"x@example.com\" <x@example.com>"), Ok(()));
+        assert_eq!(
+            check("From: a@example.org\r\nReply-To: \"boss@EXAMPLE.net\" <mallory@example.com>"),
+            Err(REPLY_TO_SPOOFED_550.to_string())
+        );
+        assert_eq!(
+            check("From: a@example.org\r\nReply-To: list@example.com, \"Boss (b@example.org)\" <b@example.com>"),
+            Err(REPLY_TO_SPOOFED_550.to_string())
+        );
+    }
+
+    #[test]
+    fn expired_deadline_stops_checks() {
+        let policy = OpenPgpPolicy::default();
+        let expired = Deadline::after(Duration::ZERO);
+        let data = partial_seipd_message();
+        assert_eq!(check_openpgp_payload(&data, &policy, expired), Err(PacketReject::Timeout));
+        let armored = armor::encode("PGP MESSAGE", &data);
+        assert!(check_armored_payload(&armored, true, &policy, expired).is_err());
+        let parsed = armor::parse(&armored, "PGP MESSAGE", &[], &policy.armor).unwrap();
+        assert_eq!(armor::decode_body(parsed.body, &policy.armor, expired), Err(ArmorError::Timeout));
+    }
+
+    fn limits(max_depth: usize, max_parts: usize, max_headers: usize) -> MimeLimits {
+        MimeLimits { max_depth, max_parts, max_headers, max_decoded_size: usize::MAX }
+    }
+
+    /// Runs the pre-scan and the check of the parsed message, which should agree.
+    fn mime_limits(raw: &str, limits: &MimeLimits) -> (bool, bool) {
+        let message = MessageParser::default().parse(raw.as_bytes()).unwrap();
+        (
+            prescan_mime_limits(raw.as_bytes(), limits).is_ok(),
+            check_mime_limits(&message, limits, Deadline::NONE).is_ok(),
+        )
+    }
+
+    /// `depth` multiparts nested inside each other, each with a text part.
+    fn nested_multiparts(depth: usize) -> String {
+        let mut mail = "Subject: nested\r\n".to_string();
+        for i in 0..depth {
+            mail += &format!("Content-Type: multipart/mixed; boundary=\"B{i}\"\r\n\r\n--B{i}\r\n\r\ntext\r\n--B{i}\r\n");
+        }
+        mail += "\r\nleaf\r\n";
+        for i in (0..depth).rev() {
+            mail += &format!("--B{i}--\r\n");
+        }
+        mail
+    }
+
+    #[test]
+    fn prescan_matches_parsed_limits() {
+        // Three nested multiparts: the innermost leaf is at depth 3, with 7 parts in total.
+        let nested = nested_multiparts(3);
+        assert_eq!(mime_limits(&nested, &limits(3, 7, 10)), (true, true));
+        assert_eq!(mime_limits(&nested, &limits(2, 7, 10)), (false, false));
+        assert_eq!(mime_limits(&nested, &limits(3, 6, 10)), (false, false));
+
+        // Attached messages count as a part and a nesting level.
+        let attached = "Content-Type: multipart/mixed; boundary=\"Outer\"\r\n\r\n--Outer\r\n\
+            Content-Type: message/rfc822\r\n\r\nSubject: inner\r\nX-A: 1\r\nX-B: 2\r\n\r\nhi\r\n--Outer--\r\n";
+        assert_eq!(mime_limits(attached, &limits(2, 3, 3)), (true, true));
+        assert_eq!(mime_limits(attached, &limits(1, 3, 3)), (false, false));
+        assert_eq!(mime_limits(attached, &limits(2, 2, 3)), (false, false));
+        assert_eq!(mime_limits(attached, &limits(2, 3, 2)), (false, false));
+
+        // Boundaries are case-sensitive, and folded Content-Type headers are followed.
+        let folded = "Content-Type: multipart/mixed;\r\n\tBoundary=\"AbC\"\r\n\r\n--abc\r\n--AbC\r\n\r\nx\r\n--AbC--\r\n";
+        assert_eq!(mime_limits(folded, &limits(1, 2, 1)), (true, true));
+        assert_eq!(mime_limits(folded, &limits(1, 1, 1)), (false, false));
+    }
 }
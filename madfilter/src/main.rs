@@ -1,13 +1,23 @@
+mod armor;
+mod check_pool;
 mod config;
 mod filter;
+mod metrics;
+mod openpgp;
+mod proxy_protocol;
 mod rate_limit;
+mod smime;
 mod smtp;
+mod transport;
 
+use check_pool::CheckPool;
 use clap::Parser;
 use config::Config;
+use metrics::Metrics;
 use rate_limit::SendRateLimiter;
 use smtp::SmtpProxy;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -15,6 +25,7 @@ struct Args {
     #[arg(index = 1)]
     config_path: String,
 
+    /// 'incoming', 'outgoing', or 'both' to serve both modes from one process.
     #[arg(index = 2)]
     mode: String,
 }
@@ -22,16 +33,40 @@ struct Args {
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    
-    if args.mode != "incoming" && args.mode != "outgoing" {
-        anyhow::bail!("Mode must be 'incoming' or 'outgoing'");
-    }
 
-    let config = Config::from_file(&args.config_path)?;
+    let modes: &[&str] = match args.mode.as_str() {
+        "incoming" => &["incoming"],
+        "outgoing" => &["outgoing"],
+        "both" => &["outgoing", "incoming"],
+        _ => anyhow::bail!("Mode must be 'incoming', 'outgoing' or 'both'"),
+    };
+
+    let config = Arc::new(Config::from_file(&args.config_path)?);
     let rate_limiter = Arc::new(SendRateLimiter::new());
-    let proxy = SmtpProxy::new(Arc::new(config), rate_limiter, args.mode);
+    let check_pool = Arc::new(CheckPool::new(config.max_concurrent_checks, config.max_queued_checks));
+    let metrics = Arc::new(Metrics::default());
+
+    if config.metrics_log_interval > 0 {
+        let metrics = Arc::clone(&metrics);
+        let interval = Duration::from_secs(config.metrics_log_interval);
+        tokio::spawn(async move { metrics.log_every(interval, modes).await });
+    }
+
+    // Each proxy only returns on error, which stops the whole process.
+    let mut tasks = tokio::task::JoinSet::new();
+    for mode in modes {
+        let proxy = SmtpProxy::new(
+            Arc::clone(&config),
+            Arc::clone(&rate_limiter),
+            Arc::clone(&check_pool),
+            Arc::clone(&metrics),
+            mode.to_string(),
+        );
+        tasks.spawn(async move { proxy.run().await });
+    }
+    if let Some(result) = tasks.join_next().await {
+        result??;
+    }
 
-    proxy.run().await?;
-    
     Ok(())
 }
@@ -0,0 +1,52 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Message counters for one filter mode.
+#[derive(Default)]
+pub struct ModeCounters {
+    pub connections: AtomicU64,
+    pub accepted: AtomicU64,
+    pub rejected: AtomicU64,
+    pub reinject_failed: AtomicU64,
+}
+
+impl ModeCounters {
+    fn summary(&self) -> String {
+        format!(
+            "connections={} accepted={} rejected={} reinject_failed={}",
+            self.connections.load(Ordering::Relaxed),
+            self.accepted.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+            self.reinject_failed.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Counters shared by all listeners of the process.
+#[derive(Default)]
+pub struct Metrics {
+    pub incoming: ModeCounters,
+    pub outgoing: ModeCounters,
+}
+
+impl Metrics {
+    pub fn for_mode(&self, mode: &str) -> &ModeCounters {
+        if mode == "outgoing" {
+            &self.outgoing
+        } else {
+            &self.incoming
+        }
+    }
+
+    /// Periodically logs the counters of the given modes.
+    pub async fn log_every(&self, interval: Duration, modes: &[&str]) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            for mode in modes {
+                eprintln!("METRICS: {} {}", mode, self.for_mode(mode).summary());
+            }
+        }
+    }
+}
@@ -0,0 +1,707 @@
+use std::fmt;
+use std::ops::Range;
+
+/// Packet header encoding, see RFC 9580 section 4.2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderFormat {
+    /// OpenPGP (formerly "new") format.
+    OpenPgp,
+    /// Legacy (formerly "old") format.
+    Legacy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketHeader {
+    pub tag: u8,
+    pub format: HeaderFormat,
+    /// Location of the body in the input. For partial-length packets this
+    /// spans all chunks, including the length octets between them.
+    pub body_range: Range<usize>,
+    /// Whether the body is split into partial-length chunks.
+    pub partial: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketErrorKind {
+    /// The packet tag octet does not have its high bit set.
+    InvalidTag,
+    /// The input ends inside a packet header or body.
+    Truncated,
+    /// A length does not fit into the address space.
+    LengthOverflow,
+}
+
+/// An error in the packet stream, with the offset of the offending packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketError {
+    pub offset: usize,
+    pub kind: PacketErrorKind,
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.kind {
+            PacketErrorKind::InvalidTag => "invalid packet tag",
+            PacketErrorKind::Truncated => "unexpected end of payload",
+            PacketErrorKind::LengthOverflow => "packet length overflow",
+        };
+        write!(f, "{} in packet at offset {}", what, self.offset)
+    }
+}
+
+/// Iterates over the packet headers of a binary OpenPGP message.
+///
+/// Iteration stops after the first error.
+pub struct PacketIter<'a> {
+    data: &'a [u8],
+    pos: usize,
+    failed: bool,
+}
+
+impl<'a> PacketIter<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0, failed: false }
+    }
+
+    fn octet(&self, pos: usize) -> Result<u8, PacketErrorKind> {
+        self.data.get(pos).copied().ok_or(PacketErrorKind::Truncated)
+    }
+
+    /// Reads a big-endian length of `n` octets starting at `pos`.
+    fn read_be(&self, pos: usize, n: usize) -> Result<usize, PacketErrorKind> {
+        let end = pos.checked_add(n).ok_or(PacketErrorKind::LengthOverflow)?;
+        let octets = self.data.get(pos..end).ok_or(PacketErrorKind::Truncated)?;
+        octets.iter().try_fold(0usize, |len, &b| {
+            len.checked_mul(256).and_then(|len| len.checked_add(b as usize)).ok_or(PacketErrorKind::LengthOverflow)
+        })
+    }
+
+    /// Returns the offset just past `len` octets starting at `pos`, if within the input.
+    fn skip(&self, pos: usize, len: usize) -> Result<usize, PacketErrorKind> {
+        let end = pos.checked_add(len).ok_or(PacketErrorKind::LengthOverflow)?;
+        if end > self.data.len() {
+            return Err(PacketErrorKind::Truncated);
+        }
+        Ok(end)
+    }
+
+    fn parse_packet(&self, start: usize) -> Result<(PacketHeader, usize), PacketErrorKind> {
+        let ctb = self.octet(start)?;
+        if ctb & 0x80 == 0 {
+            return Err(PacketErrorKind::InvalidTag);
+        }
+
+        if ctb & 0x40 != 0 {
+            let tag = ctb & 0x3F;
+            let mut pos = start + 1;
+            let mut body_start = None;
+            let mut partial = false;
+
+            loop {
+                let first = self.octet(pos)?;
+                let (header_len, body_len) = match first {
+                    // One-octet length.
+                    0..=191 => (1, first as usize),
+                    // Two-octet length.
+                    192..=223 => {
+                        let second = self.octet(pos + 1)?;
+                        (2, ((first as usize - 192) << 8) + second as usize + 192)
+                    }
+                    // Partial body length.
+                    224..=254 => (1, 1usize << (first & 0x1F)),
+                    // Five-octet length.
+                    255 => (5, self.read_be(pos + 1, 4)?),
+                };
+
+                let chunk_start = self.skip(pos, header_len)?;
+                body_start.get_or_insert(chunk_start);
+                pos = self.skip(chunk_start, body_len)?;
+
+                if !(224..=254).contains(&first) {
+                    break;
+                }
+                partial = true;
+            }
+
+            let body_start = body_start.unwrap_or(pos);
+            let header = PacketHeader { tag, format: HeaderFormat::OpenPgp, body_range: body_start..pos, partial };
+            Ok((header, pos))
+        } else {
+            let tag = (ctb >> 2) & 0x0F;
+            let body_start;
+            let body_len = match ctb & 0x03 {
+                0 => {
+                    body_start = start + 2;
+                    self.read_be(start + 1, 1)?
+                }
+                1 => {
+                    body_start = start + 3;
+                    self.read_be(start + 1, 2)?
+                }
+                2 => {
+                    body_start = start + 5;
+                    self.read_be(start + 1, 4)?
+                }
+                // Indeterminate length, the packet extends to the end of the input.
+                _ => {
+                    body_start = start + 1;
+                    self.data.len() - body_start
+                }
+            };
+            let end = self.skip(body_start, body_len)?;
+            let header = PacketHeader { tag, format: HeaderFormat::Legacy, body_range: body_start..end, partial: false };
+            Ok((header, end))
+        }
+    }
+}
+
+impl Iterator for PacketIter<'_> {
+    type Item = Result<PacketHeader, PacketError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.pos >= self.data.len() {
+            return None;
+        }
+        match self.parse_packet(self.pos) {
+            Ok((header, end)) => {
+                self.pos = end;
+                Some(Ok(header))
+            }
+            Err(kind) => {
+                self.failed = true;
+                Some(Err(PacketError { offset: self.pos, kind }))
+            }
+        }
+    }
+}
+
+/// Number of leading body octets [`PacketStream`] keeps for each packet.
+///
+/// Enough for the version octet and recipient of any PKESK packet.
+pub const BODY_PREFIX_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+enum StreamState {
+    /// Expecting a packet tag octet.
+    Tag,
+    /// Reading the length octets of an OpenPGP format header.
+    Length { octets: [u8; 5], have: usize },
+    /// Reading the length octets of a legacy format header.
+    LegacyLength { need: usize, have: usize, len: usize },
+    /// Inside a body chunk; `last` is false for partial-length chunks.
+    Body { remaining: usize, last: bool },
+    /// Inside a legacy packet of indeterminate length.
+    Indeterminate,
+}
+
+/// Incremental packet parser for messages that arrive in chunks.
+///
+/// Reports each packet once it is complete, together with the first
+/// [`BODY_PREFIX_LEN`] octets of its body, so memory use does not depend on
+/// packet sizes.
+pub struct PacketStream {
+    state: StreamState,
+    offset: usize,
+    packet_start: usize,
+    tag: u8,
+    format: HeaderFormat,
+    partial: bool,
+    body_start: Option<usize>,
+    prefix: [u8; BODY_PREFIX_LEN],
+    prefix_len: usize,
+}
+
+impl PacketStream {
+    pub fn new() -> Self {
+        Self {
+            state: StreamState::Tag,
+            offset: 0,
+            packet_start: 0,
+            tag: 0,
+            format: HeaderFormat::OpenPgp,
+            partial: false,
+            body_start: None,
+            prefix: [0; BODY_PREFIX_LEN],
+            prefix_len: 0,
+        }
+    }
+
+    fn error(&self, kind: PacketErrorKind) -> PacketError {
+        PacketError { offset: self.packet_start, kind }
+    }
+
+    fn header(&self) -> PacketHeader {
+        PacketHeader {
+            tag: self.tag,
+            format: self.format,
+            body_range: self.body_start.unwrap_or(self.offset)..self.offset,
+            partial: self.partial,
+        }
+    }
+
+    /// Starts a body chunk of `len` octets at the current offset.
+    fn start_body(&mut self, len: usize, last: bool) {
+        self.body_start.get_or_insert(self.offset);
+        self.state = StreamState::Body { remaining: len, last };
+    }
+
+    /// Parses the next chunk of the message, calling `on_packet` for every completed packet.
+    pub fn feed<E, F>(&mut self, mut data: &[u8], mut on_packet: F) -> Result<(), E>
+    where
+        E: From<PacketError>,
+        F: FnMut(&PacketHeader, &[u8]) -> Result<(), E>,
+    {
+        while !data.is_empty() {
+            match self.state {
+                StreamState::Body { remaining, last } => {
+                    let n = remaining.min(data.len());
+                    let keep = n.min(BODY_PREFIX_LEN - self.prefix_len);
+                    self.prefix[self.prefix_len..self.prefix_len + keep].copy_from_slice(&data[..keep]);
+                    self.prefix_len += keep;
+                    self.offset = self.offset.checked_add(n).ok_or(self.error(PacketErrorKind::LengthOverflow))?;
+                    data = &data[n..];
+
+                    if n == remaining {
+                        if last {
+                            on_packet(&self.header(), &self.prefix[..self.prefix_len])?;
+                            self.state = StreamState::Tag;
+                        } else {
+                            self.partial = true;
+                            self.state = StreamState::Length { octets: [0; 5], have: 0 };
+                        }
+                    } else {
+                        self.state = StreamState::Body { remaining: remaining - n, last };
+                    }
+                    continue;
+                }
+                StreamState::Indeterminate => {
+                    let keep = data.len().min(BODY_PREFIX_LEN - self.prefix_len);
+                    self.prefix[self.prefix_len..self.prefix_len + keep].copy_from_slice(&data[..keep]);
+                    self.prefix_len += keep;
+                    self.offset = self.offset.checked_add(data.len()).ok_or(self.error(PacketErrorKind::LengthOverflow))?;
+                    data = &[];
+                    continue;
+                }
+                _ => {}
+            }
+
+            // Header octets are consumed one at a time.
+            let octet = data[0];
+            data = &data[1..];
+            self.offset = self.offset.checked_add(1).ok_or(self.error(PacketErrorKind::LengthOverflow))?;
+
+            match self.state {
+                StreamState::Tag => {
+                    self.packet_start = self.offset - 1;
+                    self.body_start = None;
+                    self.prefix_len = 0;
+                    self.partial = false;
+                    if octet & 0x80 == 0 {
+                        return Err(self.error(PacketErrorKind::InvalidTag).into());
+                    }
+                    if octet & 0x40 != 0 {
+                        self.tag = octet & 0x3F;
+                        self.format = HeaderFormat::OpenPgp;
+                        self.state = StreamState::Length { octets: [0; 5], have: 0 };
+                    } else {
+                        self.tag = (octet >> 2) & 0x0F;
+                        self.format = HeaderFormat::Legacy;
+                        self.state = match octet & 0x03 {
+                            0 => StreamState::LegacyLength { need: 1, have: 0, len: 0 },
+                            1 => StreamState::LegacyLength { need: 2, have: 0, len: 0 },
+                            2 => StreamState::LegacyLength { need: 4, have: 0, len: 0 },
+                            // Indeterminate length, the packet extends to the end of the input.
+                            _ => {
+                                self.body_start = Some(self.offset);
+                                StreamState::Indeterminate
+                            }
+                        };
+                    }
+                }
+                StreamState::Length { mut octets, have } => {
+                    octets[have] = octet;
+                    let have = have + 1;
+                    match (octets[0], have) {
+                        // One-octet length.
+                        (0..=191, _) => self.start_body(octets[0] as usize, true),
+                        // Two-octet length.
+                        (192..=223, 2) => {
+                            self.start_body(((octets[0] as usize - 192) << 8) + octets[1] as usize + 192, true)
+                        }
+                        // Partial body length.
+                        (224..=254, _) => self.start_body(1usize << (octets[0] & 0x1F), false),
+                        // Five-octet length.
+                        (255, 5) => {
+                            let len = u32::from_be_bytes([octets[1], octets[2], octets[3], octets[4]]);
+                            let len = usize::try_from(len).map_err(|_| self.error(PacketErrorKind::LengthOverflow))?;
+                            self.start_body(len, true)
+                        }
+                        _ => self.state = StreamState::Length { octets, have },
+                    }
+                }
+                StreamState::LegacyLength { need, have, len } => {
+                    let len = len
+                        .checked_mul(256)
+                        .and_then(|len| len.checked_add(octet as usize))
+                        .ok_or(self.error(PacketErrorKind::LengthOverflow))?;
+                    if have + 1 == need {
+                        self.start_body(len, true);
+                    } else {
+                        self.state = StreamState::LegacyLength { need, have: have + 1, len };
+                    }
+                }
+                StreamState::Body { .. } | StreamState::Indeterminate => unreachable!(),
+            }
+
+            // Empty bodies and empty final chunks complete without further input.
+            if let StreamState::Body { remaining: 0, last: true } = self.state {
+                on_packet(&self.header(), &self.prefix[..self.prefix_len])?;
+                self.state = StreamState::Tag;
+            }
+        }
+        Ok(())
+    }
+
+    /// Signals the end of the message.
+    pub fn finish<E, F>(self, mut on_packet: F) -> Result<(), E>
+    where
+        E: From<PacketError>,
+        F: FnMut(&PacketHeader, &[u8]) -> Result<(), E>,
+    {
+        match self.state {
+            StreamState::Tag => Ok(()),
+            StreamState::Indeterminate => on_packet(&self.header(), &self.prefix[..self.prefix_len]),
+            _ => Err(self.error(PacketErrorKind::Truncated).into()),
+        }
+    }
+}
+
+/// The key a Public-Key Encrypted Session Key packet is addressed to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecipientKey {
+    /// Key ID of a v3 PKESK. All zeroes denotes a wildcard.
+    KeyId([u8; 8]),
+    /// Key version and fingerprint of a v6 PKESK.
+    Fingerprint { version: u8, fingerprint: Vec<u8> },
+    /// A v6 PKESK without recipient information.
+    Anonymous,
+}
+
+impl RecipientKey {
+    /// Parses the recipient of a PKESK packet body, returning `None` if it is malformed.
+    pub fn from_pkesk(body: &[u8]) -> Option<Self> {
+        match body.first()? {
+            3 => Some(RecipientKey::KeyId(body.get(1..9)?.try_into().ok()?)),
+            6 => {
+                let len = *body.get(1)? as usize;
+                if len == 0 {
+                    return Some(RecipientKey::Anonymous);
+                }
+                let version = *body.get(2)?;
+                let fingerprint = body.get(3..2 + len)?.to_vec();
+                Some(RecipientKey::Fingerprint { version, fingerprint })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RecipientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let octets = match self {
+            RecipientKey::KeyId(id) => id.as_slice(),
+            RecipientKey::Fingerprint { fingerprint, .. } => fingerprint.as_slice(),
+            RecipientKey::Anonymous => return write!(f, "anonymous"),
+        };
+        octets.iter().try_for_each(|b| write!(f, "{:02X}", b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// How the reference encoder writes the length of a packet.
+    #[derive(Debug, Clone)]
+    enum Length {
+        OneOctet,
+        TwoOctet,
+        FiveOctet,
+        /// Partial chunks of `1 << exponent` octets each, followed by a
+        /// definite final chunk with the rest of the body.
+        Partial(Vec<u8>),
+        /// Legacy header with a 1-, 2- or 4-octet length.
+        Legacy(usize),
+        /// Legacy header of indeterminate length, only valid for the last packet.
+        Indeterminate,
+    }
+
+    #[derive(Debug, Clone)]
+    struct Packet {
+        tag: u8,
+        length: Length,
+        body: Vec<u8>,
+    }
+
+    /// Writes a one-, two- or five-octet OpenPGP format length.
+    fn encode_definite(out: &mut Vec<u8>, len: usize) {
+        match len {
+            0..=191 => out.push(len as u8),
+            192..=8383 => {
+                let len = len - 192;
+                out.extend_from_slice(&[(len >> 8) as u8 + 192, len as u8]);
+            }
+            _ => {
+                out.push(255);
+                out.extend_from_slice(&(len as u32).to_be_bytes());
+            }
+        }
+    }
+
+    /// Reference encoder. Returns the message and the header each packet must parse to.
+    fn encode(packets: &[Packet]) -> (Vec<u8>, Vec<PacketHeader>) {
+        let mut out = Vec::new();
+        let mut headers = Vec::new();
+        for packet in packets {
+            let body = packet.body.as_slice();
+            let (format, partial) = match &packet.length {
+                Length::Legacy(_) | Length::Indeterminate => (HeaderFormat::Legacy, false),
+                Length::Partial(exponents) => (HeaderFormat::OpenPgp, !exponents.is_empty()),
+                _ => (HeaderFormat::OpenPgp, false),
+            };
+            let body_start;
+            match &packet.length {
+                Length::OneOctet | Length::TwoOctet => {
+                    out.push(0xC0 | packet.tag);
+                    encode_definite(&mut out, body.len());
+                    body_start = out.len();
+                    out.extend_from_slice(body);
+                }
+                Length::FiveOctet => {
+                    out.push(0xC0 | packet.tag);
+                    out.push(255);
+                    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+                    body_start = out.len();
+                    out.extend_from_slice(body);
+                }
+                Length::Partial(exponents) => {
+                    out.push(0xC0 | packet.tag);
+                    let mut rest = body;
+                    let mut start = None;
+                    for &exponent in exponents {
+                        let chunk = &rest[..1 << exponent];
+                        out.push(224 + exponent);
+                        start.get_or_insert(out.len());
+                        out.extend_from_slice(chunk);
+                        rest = &rest[chunk.len()..];
+                    }
+                    encode_definite(&mut out, rest.len());
+                    body_start = *start.get_or_insert(out.len());
+                    out.extend_from_slice(rest);
+                }
+                Length::Legacy(octets) => {
+                    let length_type = match octets {
+                        1 => 0,
+                        2 => 1,
+                        _ => 2,
+                    };
+                    out.push(0x80 | packet.tag << 2 | length_type);
+                    out.extend_from_slice(&(body.len() as u32).to_be_bytes()[4 - octets..]);
+                    body_start = out.len();
+                    out.extend_from_slice(body);
+                }
+                Length::Indeterminate => {
+                    out.push(0x80 | packet.tag << 2 | 3);
+                    body_start = out.len();
+                    out.extend_from_slice(body);
+                }
+            }
+            headers.push(PacketHeader { tag: packet.tag, format, body_range: body_start..out.len(), partial });
+        }
+        (out, headers)
+    }
+
+    /// Parses `data` with [`PacketIter`].
+    fn iterate(data: &[u8]) -> (Vec<PacketHeader>, Option<PacketError>) {
+        let mut headers = Vec::new();
+        for header in PacketIter::new(data) {
+            match header {
+                Ok(header) => headers.push(header),
+                Err(e) => return (headers, Some(e)),
+            }
+        }
+        (headers, None)
+    }
+
+    /// Feeds `data` to a [`PacketStream`] in chunks of `chunk` octets and
+    /// collects the reported packets with their body prefixes.
+    fn stream(data: &[u8], chunk: usize) -> (Vec<(PacketHeader, Vec<u8>)>, Option<PacketError>) {
+        let mut packets = Vec::new();
+        let mut on_packet = |header: &PacketHeader, body: &[u8]| {
+            packets.push((header.clone(), body.to_vec()));
+            Ok::<_, PacketError>(())
+        };
+        let mut stream = PacketStream::new();
+        let result = data
+            .chunks(chunk)
+            .try_for_each(|part| stream.feed(part, &mut on_packet))
+            .and_then(|()| stream.finish(&mut on_packet));
+        (packets, result.err())
+    }
+
+    /// Checks that [`PacketStream`] agrees with [`PacketIter`] on `data` for several chunk sizes.
+    fn assert_stream_matches_iter(data: &[u8]) -> Result<(), TestCaseError> {
+        let (headers, error) = iterate(data);
+        for chunk in [1, 2, 3, 7, 64, data.len().max(1)] {
+            let (packets, stream_error) = stream(data, chunk);
+            let stream_headers: Vec<_> = packets.into_iter().map(|(header, _)| header).collect();
+            prop_assert_eq!(&stream_headers, &headers, "chunk size {}", chunk);
+            prop_assert_eq!(stream_error, error, "chunk size {}", chunk);
+        }
+        Ok(())
+    }
+
+    fn body(len: impl Strategy<Value = usize>) -> impl Strategy<Value = Vec<u8>> {
+        len.prop_flat_map(|len| prop::collection::vec(any::<u8>(), len))
+    }
+
+    /// A packet in any header form except indeterminate length.
+    fn packet() -> impl Strategy<Value = Packet> {
+        let openpgp = (0u8..64).prop_flat_map(|tag| {
+            prop_oneof![
+                body(0usize..192).prop_map(move |body| Packet { tag, length: Length::OneOctet, body }),
+                body(192usize..=8383).prop_map(move |body| Packet { tag, length: Length::TwoOctet, body }),
+                body(0usize..600).prop_map(move |body| Packet { tag, length: Length::FiveOctet, body }),
+                (prop::collection::vec(0u8..10, 1..4), 0usize..300).prop_flat_map(move |(exponents, rest)| {
+                    let len = exponents.iter().map(|&e| 1usize << e).sum::<usize>() + rest;
+                    body(Just(len)).prop_map(move |body| Packet {
+                        tag,
+                        length: Length::Partial(exponents.clone()),
+                        body,
+                    })
+                }),
+            ]
+        });
+        let legacy = (0u8..16, prop_oneof![Just(1usize), Just(2), Just(4)]).prop_flat_map(|(tag, octets)| {
+            let max: usize = if octets == 1 { 256 } else { 600 };
+            body(0..max).prop_map(move |body| Packet { tag, length: Length::Legacy(octets), body })
+        });
+        prop_oneof![openpgp, legacy]
+    }
+
+    /// Up to five packets, the last optionally of indeterminate length.
+    fn message() -> impl Strategy<Value = Vec<Packet>> {
+        let last = prop_oneof![
+            packet().prop_map(Some),
+            (0u8..16, body(0usize..300))
+                .prop_map(|(tag, body)| Some(Packet { tag, length: Length::Indeterminate, body })),
+            Just(None),
+        ];
+        (prop::collection::vec(packet(), 0..5), last).prop_map(|(mut packets, last)| {
+            packets.extend(last);
+            packets
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn iter_round_trips(packets in message()) {
+            let (data, expected) = encode(&packets);
+            let (headers, error) = iterate(&data);
+            prop_assert_eq!(error, None);
+            prop_assert_eq!(&headers, &expected);
+            for (header, packet) in headers.iter().zip(&packets) {
+                if !header.partial {
+                    prop_assert_eq!(&data[header.body_range.clone()], packet.body.as_slice());
+                }
+            }
+        }
+
+        #[test]
+        fn stream_reports_body_prefix(packets in message(), chunk in 1usize..100) {
+            let (data, expected) = encode(&packets);
+            let (reported, error) = stream(&data, chunk);
+            prop_assert_eq!(error, None);
+            prop_assert_eq!(reported.len(), packets.len());
+            for ((header, prefix), (packet, expected)) in reported.iter().zip(packets.iter().zip(&expected)) {
+                prop_assert_eq!(header, expected);
+                prop_assert_eq!(prefix.as_slice(), &packet.body[..packet.body.len().min(BODY_PREFIX_LEN)]);
+            }
+        }
+
+        #[test]
+        fn truncation_is_reported_at_the_cut_packet(
+            packets in prop::collection::vec(packet(), 1..5),
+            cut in any::<prop::sample::Index>(),
+        ) {
+            let (data, expected) = encode(&packets);
+            let cut = cut.index(data.len());
+            let (headers, error) = iterate(&data[..cut]);
+
+            let complete = expected.iter().take_while(|h| h.body_range.end <= cut).count();
+            prop_assert_eq!(headers.as_slice(), &expected[..complete]);
+            let start = expected[..complete].last().map_or(0, |h| h.body_range.end);
+            if start == cut {
+                prop_assert_eq!(error, None);
+            } else {
+                prop_assert_eq!(error, Some(PacketError { offset: start, kind: PacketErrorKind::Truncated }));
+            }
+            assert_stream_matches_iter(&data[..cut])?;
+        }
+
+        #[test]
+        fn oversized_lengths_are_truncated(
+            prefix in prop::collection::vec(packet(), 0..3),
+            header in prop_oneof![
+                (0u8..64, 601u32..).prop_map(|(tag, len)| {
+                    let mut h = vec![0xC0 | tag, 255];
+                    h.extend_from_slice(&len.to_be_bytes());
+                    h
+                }),
+                (0u8..16, 601u32..).prop_map(|(tag, len)| {
+                    let mut h = vec![0x80 | tag << 2 | 2];
+                    h.extend_from_slice(&len.to_be_bytes());
+                    h
+                }),
+                (0u8..64, 10u8..31).prop_map(|(tag, exponent)| vec![0xC0 | tag, 224 + exponent]),
+            ],
+            tail in body(0usize..600),
+        ) {
+            let (mut data, expected) = encode(&prefix);
+            let start = data.len();
+            data.extend_from_slice(&header);
+            data.extend_from_slice(&tail);
+            let (headers, error) = iterate(&data);
+            prop_assert_eq!(&headers, &expected);
+            prop_assert_eq!(error, Some(PacketError { offset: start, kind: PacketErrorKind::Truncated }));
+            assert_stream_matches_iter(&data)?;
+        }
+
+        #[test]
+        fn stream_matches_iter_on_arbitrary_input(data in prop::collection::vec(any::<u8>(), 0..2000)) {
+            assert_stream_matches_iter(&data)?;
+        }
+    }
+
+    #[test]
+    fn partial_body_with_empty_final_chunk() {
+        // SEIPD v1 split into a 2-octet partial chunk, a 1-octet partial chunk and an empty final chunk.
+        let data = [0xD2, 0xE1, 0x01, 0xAA, 0xE0, 0xBB, 0x00];
+        let expected = PacketHeader { tag: 18, format: HeaderFormat::OpenPgp, body_range: 2..7, partial: true };
+
+        assert_eq!(iterate(&data), (vec![expected.clone()], None));
+        for chunk in 1..=data.len() {
+            assert_eq!(stream(&data, chunk), (vec![(expected.clone(), vec![0x01, 0xAA, 0xBB])], None));
+        }
+    }
+
+    #[test]
+    fn invalid_tag_stops_iteration() {
+        let data = [0xC1, 0x01, 0x03, 0x7F, 0xC1, 0x00];
+        let header = PacketHeader { tag: 1, format: HeaderFormat::OpenPgp, body_range: 2..3, partial: false };
+        let error = PacketError { offset: 3, kind: PacketErrorKind::InvalidTag };
+        assert_eq!(iterate(&data), (vec![header.clone()], Some(error)));
+        assert_eq!(stream(&data, 1), (vec![(header, vec![0x03])], Some(error)));
+    }
+}
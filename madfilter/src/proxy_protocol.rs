@@ -0,0 +1,226 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY";
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest possible v1 header including the trailing CRLF.
+const V1_MAX_LEN: u64 = 107;
+
+/// An address or network allowed to send PROXY protocol headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrustedSource {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl TrustedSource {
+    /// Parses an address (`10.0.0.1`) or a network (`10.0.0.0/24`, `fd00::/8`).
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| anyhow::anyhow!("Invalid trusted proxy address: {}", s))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse().ok().filter(|&len| len <= max_len)
+                .ok_or_else(|| anyhow::anyhow!("Invalid trusted proxy prefix length: {}", s))?,
+            None => max_len,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    let shift = bits - prefix_len;
+    shift == bits || (net >> shift) == (ip >> shift)
+}
+
+/// Reads a PROXY protocol v1 or v2 header from the start of a connection.
+///
+/// Returns the original client address, or `None` if the proxy did not
+/// supply one (v1 `UNKNOWN`, v2 `LOCAL` or a non-IP address family).
+pub async fn read_header<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 5];
+    reader.read_exact(&mut prefix).await?;
+
+    if prefix == V1_PREFIX {
+        let mut line = prefix.to_vec();
+        (&mut *reader).take(V1_MAX_LEN - 5).read_until(b'\n', &mut line).await?;
+        parse_v1(&line)
+    } else if prefix == V2_SIGNATURE[..5] {
+        let mut header = [0u8; 11];
+        reader.read_exact(&mut header).await?;
+        if header[..7] != V2_SIGNATURE[5..] {
+            anyhow::bail!("Invalid PROXY v2 signature");
+        }
+        let len = u16::from_be_bytes([header[9], header[10]]) as usize;
+        let mut body = vec![0u8; len];
+        reader.read_exact(&mut body).await?;
+        parse_v2(header[7], header[8], &body)
+    } else {
+        anyhow::bail!("Missing PROXY protocol header")
+    }
+}
+
+fn parse_v1(line: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|l| l.strip_suffix("\r\n"))
+        .ok_or_else(|| anyhow::anyhow!("Malformed PROXY v1 header"))?;
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    if fields[0] != "PROXY" {
+        anyhow::bail!("Malformed PROXY v1 header");
+    }
+    match fields.get(1) {
+        Some(&"UNKNOWN") => Ok(None),
+        Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {
+            let ip: IpAddr = fields[2].parse().map_err(|_| anyhow::anyhow!("Invalid PROXY v1 source address"))?;
+            let port: u16 = fields[4].parse().map_err(|_| anyhow::anyhow!("Invalid PROXY v1 source port"))?;
+            if ip.is_ipv4() != (fields[1] == "TCP4") {
+                anyhow::bail!("PROXY v1 address does not match protocol {}", fields[1]);
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => anyhow::bail!("Malformed PROXY v1 header"),
+    }
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, body: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    if ver_cmd >> 4 != 2 {
+        anyhow::bail!("Unsupported PROXY protocol version {}", ver_cmd >> 4);
+    }
+    match ver_cmd & 0x0F {
+        // LOCAL: health checks from the proxy itself.
+        0 => return Ok(None),
+        1 => {}
+        cmd => anyhow::bail!("Unsupported PROXY v2 command {}", cmd),
+    }
+
+    match family {
+        // TCP over IPv4.
+        0x11 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // TCP over IPv6.
+        0x21 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        0x11 | 0x21 => anyhow::bail!("Truncated PROXY v2 address block"),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut data: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+        read_header(&mut data).await
+    }
+
+    fn v2(ver_cmd: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[ver_cmd, family]);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    #[tokio::test]
+    async fn parses_v1() {
+        let addr = read(b"PROXY TCP4 192.0.2.7 10.0.0.1 5555 25\r\nEHLO").await.unwrap();
+        assert_eq!(addr, Some("192.0.2.7:5555".parse().unwrap()));
+        let addr = read(b"PROXY TCP6 2001:db8::7 fd00::1 5555 25\r\n").await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::7]:5555".parse().unwrap()));
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_v1() {
+        assert!(read(b"PROXY TCP4 2001:db8::7 fd00::1 5555 25\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.7 10.0.0.1 5555\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.7 10.0.0.1 70000 25\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.7 10.0.0.1 5555 25\n").await.is_err());
+        assert!(read(b"PROXYX TCP4 192.0.2.7 10.0.0.1 5555 25\r\n").await.is_err());
+        assert!(read(b"EHLO localhost\r\n").await.is_err());
+
+        // The header is limited to 107 octets, so a longer line is never completed.
+        let mut long = b"PROXY UNKNOWN ".to_vec();
+        long.extend_from_slice(&[b'x'; 100]);
+        long.extend_from_slice(b"\r\n");
+        assert!(read(&long).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn parses_v2() {
+        let mut body = vec![192, 0, 2, 7, 10, 0, 0, 1];
+        body.extend_from_slice(&[0x15, 0xB3, 0, 25]);
+        let addr = read(&v2(0x21, 0x11, &body)).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.7:5555".parse().unwrap()));
+
+        let mut body = "2001:db8::7".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        body.extend_from_slice(&[0; 16]);
+        body.extend_from_slice(&[0x15, 0xB3, 0, 25]);
+        // TLVs after the address block are ignored.
+        body.extend_from_slice(&[0x04, 0, 1, 0]);
+        let addr = read(&v2(0x21, 0x21, &body)).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::7]:5555".parse().unwrap()));
+
+        // LOCAL connections and other address families carry no client address.
+        assert_eq!(read(&v2(0x20, 0x00, &[])).await.unwrap(), None);
+        assert_eq!(read(&v2(0x21, 0x31, &[0; 216])).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_v2() {
+        assert!(read(&v2(0x11, 0x11, &[0; 12])).await.is_err());
+        assert!(read(&v2(0x22, 0x11, &[0; 12])).await.is_err());
+        assert!(read(&v2(0x21, 0x11, &[0; 11])).await.is_err());
+        assert!(read(&v2(0x21, 0x21, &[0; 35])).await.is_err());
+        let mut truncated = v2(0x21, 0x11, &[0; 12]);
+        truncated.pop();
+        assert!(read(&truncated).await.is_err());
+        let mut bad_signature = v2(0x21, 0x11, &[0; 12]);
+        bad_signature[6] = b'X';
+        assert!(read(&bad_signature).await.is_err());
+    }
+
+    #[test]
+    fn trusted_sources() {
+        let net = TrustedSource::parse("10.0.1.0/24").unwrap();
+        assert!(net.contains("10.0.1.200".parse().unwrap()));
+        assert!(net.contains("::ffff:10.0.1.200".parse().unwrap()));
+        assert!(!net.contains("10.0.2.1".parse().unwrap()));
+        assert!(!net.contains("fd00::1".parse().unwrap()));
+
+        let host = TrustedSource::parse("fd00::2").unwrap();
+        assert!(host.contains("fd00::2".parse().unwrap()));
+        assert!(!host.contains("fd00::3".parse().unwrap()));
+
+        assert!(TrustedSource::parse("0.0.0.0/0").unwrap().contains("192.0.2.1".parse().unwrap()));
+        assert!(TrustedSource::parse("::/0").unwrap().contains("2001:db8::1".parse().unwrap()));
+        assert!(TrustedSource::parse("10.0.0.0/33").is_err());
+        assert!(TrustedSource::parse("fd00::/129").is_err());
+        assert!(TrustedSource::parse("example.org").is_err());
+    }
+}
@@ -1,21 +1,31 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
+/// When a Secure-Join request was sent and to whom.
+type SecureJoinRequest = (Instant, Vec<String>);
+
 pub struct SendRateLimiter {
     addr2timestamps: Mutex<HashMap<String, Vec<Instant>>>,
+    /// Messages per original client address, as reported by a trusted proxy.
+    client2timestamps: Mutex<HashMap<IpAddr, Vec<Instant>>>,
+    /// Unencrypted Secure-Join requests per sender, with their recipients.
+    addr2securejoins: Mutex<HashMap<String, Vec<SecureJoinRequest>>>,
 }
 
 impl SendRateLimiter {
     pub fn new() -> Self {
         Self {
             addr2timestamps: Mutex::new(HashMap::new()),
+            client2timestamps: Mutex::new(HashMap::new()),
+            addr2securejoins: Mutex::new(HashMap::new()),
         }
     }
 
     pub fn is_sending_allowed(&self, mail_from: &str, max_send_per_minute: u32) -> bool {
         let mut map = self.addr2timestamps.lock().unwrap();
-        let timestamps = map.entry(mail_from.to_string()).or_insert_with(Vec::new);
+        let timestamps = map.entry(mail_from.to_string()).or_default();
         
         let now = Instant::now();
         let minute_ago = now - Duration::from_secs(60);
@@ -30,4 +40,118 @@ impl SendRateLimiter {
             false
         }
     }
+
+    /// Records a message from `client` unless it already sent `max_send_per_minute`
+    /// messages within the last minute.
+    pub fn is_client_allowed(&self, client: IpAddr, max_send_per_minute: u32) -> bool {
+        let mut map = self.client2timestamps.lock().unwrap();
+        let timestamps = map.entry(client).or_default();
+
+        let now = Instant::now();
+        let minute_ago = now - Duration::from_secs(60);
+        timestamps.retain(|&ts| ts >= minute_ago);
+
+        if (timestamps.len() as u32) < max_send_per_minute {
+            timestamps.push(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether a Secure-Join request from `mail_from` to `rcpt_tos` stays within
+    /// the hourly number of requests and of distinct recipients.
+    ///
+    /// The request only counts once it was delivered, see [`Self::record_securejoin`].
+    pub fn is_securejoin_allowed(
+        &self,
+        mail_from: &str,
+        rcpt_tos: &[String],
+        max_per_hour: u32,
+        max_recipients_per_hour: u32,
+    ) -> bool {
+        let mut map = self.addr2securejoins.lock().unwrap();
+        // Forget senders whose requests all expired, so the map only holds the last hour.
+        let hour_ago = Instant::now() - Duration::from_secs(3600);
+        map.retain(|_, requests| {
+            requests.retain(|(ts, _)| *ts >= hour_ago);
+            !requests.is_empty()
+        });
+        let requests = map.get(&mail_from.to_lowercase()).map_or(&[][..], Vec::as_slice);
+
+        if requests.len() as u32 >= max_per_hour {
+            return false;
+        }
+        let rcpt_tos: Vec<String> = rcpt_tos.iter().map(|r| r.to_lowercase()).collect();
+        let recipients: HashSet<&String> = requests.iter().flat_map(|(_, r)| r).chain(&rcpt_tos).collect();
+        recipients.len() as u32 <= max_recipients_per_hour
+    }
+
+    /// Counts a delivered Secure-Join request from `mail_from` to `rcpt_tos`.
+    pub fn record_securejoin(&self, mail_from: &str, rcpt_tos: &[String]) {
+        let rcpt_tos = rcpt_tos.iter().map(|r| r.to_lowercase()).collect();
+        let mut map = self.addr2securejoins.lock().unwrap();
+        map.entry(mail_from.to_lowercase()).or_default().push((Instant::now(), rcpt_tos));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rcpts(r: &[&str]) -> Vec<String> {
+        r.iter().map(|r| r.to_string()).collect()
+    }
+
+    #[test]
+    fn securejoin_hourly_requests() {
+        let limiter = SendRateLimiter::new();
+        let bob = rcpts(&["bob@example.org"]);
+        for _ in 0..3 {
+            assert!(limiter.is_securejoin_allowed("alice@example.org", &bob, 3, 10));
+            limiter.record_securejoin("alice@example.org", &bob);
+        }
+        assert!(!limiter.is_securejoin_allowed("alice@example.org", &bob, 3, 10));
+        assert!(!limiter.is_securejoin_allowed("ALICE@example.org", &bob, 3, 10));
+        assert!(limiter.is_securejoin_allowed("carol@example.org", &bob, 3, 10));
+    }
+
+    #[test]
+    fn securejoin_checks_do_not_count() {
+        let limiter = SendRateLimiter::new();
+        let bob = rcpts(&["bob@example.org"]);
+        // A request that was checked but never delivered, e.g. deferred by the upstream, is not counted.
+        for _ in 0..5 {
+            assert!(limiter.is_securejoin_allowed("alice@example.org", &bob, 1, 10));
+        }
+        assert!(limiter.addr2securejoins.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn securejoin_distinct_recipients() {
+        let limiter = SendRateLimiter::new();
+        let two = rcpts(&["bob@example.org", "carol@example.org"]);
+        assert!(limiter.is_securejoin_allowed("alice@example.org", &two, 10, 2));
+        limiter.record_securejoin("alice@example.org", &two);
+
+        // Writing to the same recipients again does not use up the recipient quota.
+        assert!(limiter.is_securejoin_allowed("alice@example.org", &rcpts(&["BOB@example.org"]), 10, 2));
+        assert!(!limiter.is_securejoin_allowed("alice@example.org", &rcpts(&["dave@example.org"]), 10, 2));
+        assert!(!limiter.is_securejoin_allowed("erin@example.org", &rcpts(&["a@x", "b@x", "c@x"]), 10, 2));
+        assert!(limiter.is_securejoin_allowed("erin@example.org", &rcpts(&["a@x", "a@x", "A@x"]), 10, 2));
+    }
+
+    #[test]
+    fn securejoin_expired_senders_are_removed() {
+        let limiter = SendRateLimiter::new();
+        let expired = Instant::now() - Duration::from_secs(3601);
+        limiter
+            .addr2securejoins
+            .lock()
+            .unwrap()
+            .insert("alice@example.org".to_string(), vec![(expired, rcpts(&["bob@example.org"]))]);
+
+        assert!(limiter.is_securejoin_allowed("carol@example.org", &rcpts(&["bob@example.org"]), 1, 1));
+        assert!(limiter.addr2securejoins.lock().unwrap().is_empty());
+    }
 }
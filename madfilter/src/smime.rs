@@ -0,0 +1,368 @@
+use crate::check_pool::Deadline;
+use std::fmt;
+
+/// DER encoding of the `id-envelopedData` object identifier (1.2.840.113549.1.7.3).
+const ID_ENVELOPED_DATA: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x07, 0x03];
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_CONTEXT_0_PRIMITIVE: u8 = 0x80;
+const TAG_CONTEXT_0: u8 = 0xA0;
+const TAG_CONTEXT_1: u8 = 0xA1;
+
+/// Limit on the nesting of indefinite-length elements.
+const MAX_DEPTH: usize = 32;
+
+/// A structural problem with a CMS `EnvelopedData` object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SmimeError {
+    /// The BER/DER encoding itself is broken.
+    Malformed(&'static str),
+    /// An element does not have the tag required by RFC 5652.
+    UnexpectedTag { expected: &'static str, tag: u8 },
+    NotEnvelopedData,
+    UnsupportedVersion(u8),
+    NoRecipientInfos,
+    MissingEncryptedContent,
+    TrailingData,
+    /// The check ran out of time.
+    Timeout,
+}
+
+impl fmt::Display for SmimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmimeError::Malformed(what) => write!(f, "Malformed CMS encoding: {}", what),
+            SmimeError::UnexpectedTag { expected, tag } => {
+                write!(f, "Expected {} in CMS structure, got tag {:#04x}", expected, tag)
+            }
+            SmimeError::NotEnvelopedData => write!(f, "CMS content type is not id-envelopedData"),
+            SmimeError::UnsupportedVersion(v) => write!(f, "Unsupported EnvelopedData version {}", v),
+            SmimeError::NoRecipientInfos => write!(f, "EnvelopedData has no recipientInfos"),
+            SmimeError::MissingEncryptedContent => write!(f, "EnvelopedData has no encrypted content"),
+            SmimeError::TrailingData => write!(f, "Trailing data after CMS structure"),
+            SmimeError::Timeout => write!(f, "timeout: check took too long"),
+        }
+    }
+}
+
+/// A single BER element. For indefinite-length elements `content` excludes
+/// the end-of-contents octets.
+struct Element<'a> {
+    tag: u8,
+    content: &'a [u8],
+}
+
+/// Reads BER elements from a byte slice.
+///
+/// S/MIME agents commonly emit indefinite lengths for constructed elements
+/// (streaming BER), so those are accepted in addition to DER.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self::nested(data, 0)
+    }
+
+    fn nested(data: &'a [u8], depth: usize) -> Self {
+        Self { data, pos: 0, depth }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn octet(&mut self) -> Result<u8, SmimeError> {
+        let b = *self.data.get(self.pos).ok_or(SmimeError::Malformed("unexpected end of data"))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn read(&mut self) -> Result<Element<'a>, SmimeError> {
+        let tag = self.octet()?;
+        if tag & 0x1F == 0x1F {
+            return Err(SmimeError::Malformed("high tag numbers are not used in CMS"));
+        }
+
+        let first = self.octet()?;
+        if first == 0x80 {
+            if tag & 0x20 == 0 {
+                return Err(SmimeError::Malformed("indefinite length on primitive element"));
+            }
+            if self.depth >= MAX_DEPTH {
+                return Err(SmimeError::Malformed("nesting too deep"));
+            }
+            // Skip over the children until the end-of-contents marker.
+            let start = self.pos;
+            let mut inner = Reader::nested(&self.data[start..], self.depth + 1);
+            loop {
+                if inner.data[inner.pos..].starts_with(&[0, 0]) {
+                    let end = start + inner.pos;
+                    self.pos = end + 2;
+                    return Ok(Element { tag, content: &self.data[start..end] });
+                }
+                inner.read()?;
+            }
+        }
+
+        let len = if first & 0x80 == 0 {
+            first as usize
+        } else {
+            let n = (first & 0x7F) as usize;
+            if n > std::mem::size_of::<usize>() {
+                return Err(SmimeError::Malformed("length overflow"));
+            }
+            let mut len = 0usize;
+            for _ in 0..n {
+                len = (len << 8) | self.octet()? as usize;
+            }
+            len
+        };
+
+        let end = self.pos.checked_add(len).ok_or(SmimeError::Malformed("length overflow"))?;
+        let content = self.data.get(self.pos..end).ok_or(SmimeError::Malformed("unexpected end of data"))?;
+        self.pos = end;
+        Ok(Element { tag, content })
+    }
+
+    /// Reads the next element and checks its tag.
+    fn expect(&mut self, tag: u8, expected: &'static str) -> Result<Element<'a>, SmimeError> {
+        let element = self.read()?;
+        if element.tag != tag {
+            return Err(SmimeError::UnexpectedTag { expected, tag: element.tag });
+        }
+        Ok(element)
+    }
+
+    /// Returns the tag of the next element without consuming it.
+    fn peek_tag(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn children(&self, element: &Element<'a>) -> Reader<'a> {
+        Reader::nested(element.content, self.depth + 1)
+    }
+}
+
+/// Checks that `der` is a CMS `ContentInfo` wrapping an `EnvelopedData`
+/// (RFC 5652 section 6.1) with a known version, at least one recipient and
+/// non-empty encrypted content.
+///
+/// The recipient infos and algorithm parameters are not interpreted.
+pub fn check_enveloped_data(der: &[u8], deadline: Deadline) -> Result<(), SmimeError> {
+    let mut top = Reader::new(der);
+    let content_info = top.expect(TAG_SEQUENCE, "ContentInfo SEQUENCE")?;
+    if !top.is_empty() {
+        return Err(SmimeError::TrailingData);
+    }
+
+    let mut ci = top.children(&content_info);
+    let content_type = ci.expect(TAG_OID, "contentType OID")?;
+    if content_type.content != ID_ENVELOPED_DATA {
+        return Err(SmimeError::NotEnvelopedData);
+    }
+    let explicit = ci.expect(TAG_CONTEXT_0, "[0] content")?;
+
+    let mut wrapper = ci.children(&explicit);
+    let enveloped = wrapper.expect(TAG_SEQUENCE, "EnvelopedData SEQUENCE")?;
+    let mut ed = wrapper.children(&enveloped);
+
+    let version = ed.expect(TAG_INTEGER, "version INTEGER")?;
+    match version.content {
+        [0 | 2 | 3 | 4] => {}
+        [v] => return Err(SmimeError::UnsupportedVersion(*v)),
+        _ => return Err(SmimeError::Malformed("version is not a small integer")),
+    }
+
+    // originatorInfo [0] IMPLICIT OriginatorInfo OPTIONAL
+    if ed.peek_tag() == Some(TAG_CONTEXT_0) {
+        ed.read()?;
+    }
+
+    let recipient_infos = ed.expect(TAG_SET, "recipientInfos SET")?;
+    let mut recipients = ed.children(&recipient_infos);
+    if recipients.is_empty() {
+        return Err(SmimeError::NoRecipientInfos);
+    }
+    while !recipients.is_empty() {
+        if deadline.is_expired() {
+            return Err(SmimeError::Timeout);
+        }
+        recipients.read()?;
+    }
+
+    let encrypted_content_info = ed.expect(TAG_SEQUENCE, "EncryptedContentInfo SEQUENCE")?;
+    let mut eci = ed.children(&encrypted_content_info);
+    eci.expect(TAG_OID, "content type OID")?;
+    eci.expect(TAG_SEQUENCE, "contentEncryptionAlgorithm")?;
+    let encrypted_content = match eci.peek_tag() {
+        Some(TAG_CONTEXT_0_PRIMITIVE | TAG_CONTEXT_0) => eci.read()?,
+        _ => return Err(SmimeError::MissingEncryptedContent),
+    };
+    if !has_content(&eci, &encrypted_content, deadline)? {
+        return Err(SmimeError::MissingEncryptedContent);
+    }
+    if !eci.is_empty() {
+        return Err(SmimeError::TrailingData);
+    }
+
+    // unprotectedAttrs [1] IMPLICIT UnprotectedAttributes OPTIONAL
+    if ed.peek_tag() == Some(TAG_CONTEXT_1) {
+        ed.read()?;
+    }
+    if !ed.is_empty() || !wrapper.is_empty() || !ci.is_empty() {
+        return Err(SmimeError::TrailingData);
+    }
+
+    Ok(())
+}
+
+/// Whether the encrypted content holds any octets. Constructed (BER) encodings
+/// split the content into OCTET STRING segments.
+fn has_content(parent: &Reader<'_>, element: &Element<'_>, deadline: Deadline) -> Result<bool, SmimeError> {
+    if element.tag == TAG_CONTEXT_0_PRIMITIVE {
+        return Ok(!element.content.is_empty());
+    }
+    let mut segments = parent.children(element);
+    let mut any = false;
+    while !segments.is_empty() {
+        if deadline.is_expired() {
+            return Err(SmimeError::Timeout);
+        }
+        let segment = segments.expect(TAG_OCTET_STRING, "encryptedContent OCTET STRING")?;
+        any |= !segment.content.is_empty();
+    }
+    Ok(any)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// DER encoding of the `id-data` object identifier (1.2.840.113549.1.7.1).
+    const ID_DATA: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x07, 0x01];
+    /// DER encoding of `aes256-CBC` (2.16.840.1.101.3.4.1.42).
+    const AES256_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2A];
+
+    /// Encodes an element with a definite length.
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+        match content.len() {
+            len @ 0..=0x7F => out.push(len as u8),
+            len @ 0x80..=0xFF => out.extend_from_slice(&[0x81, len as u8]),
+            len => out.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+        }
+        out.extend_from_slice(content);
+        out
+    }
+
+    /// Encodes a constructed element with an indefinite length.
+    fn indefinite(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag, 0x80];
+        out.extend_from_slice(content);
+        out.extend_from_slice(&[0, 0]);
+        out
+    }
+
+    /// Builds a ContentInfo around EnvelopedData with the given fields.
+    fn enveloped(version: u8, recipients: &[u8], encrypted_content: &[u8], extra: &[u8]) -> Vec<u8> {
+        let algorithm = tlv(TAG_SEQUENCE, &tlv(TAG_OID, AES256_CBC));
+        let eci = [tlv(TAG_OID, ID_DATA), algorithm, encrypted_content.to_vec()].concat();
+        let ed = [
+            tlv(TAG_INTEGER, &[version]),
+            tlv(TAG_SET, recipients),
+            tlv(TAG_SEQUENCE, &eci),
+            extra.to_vec(),
+        ]
+        .concat();
+        let content = tlv(TAG_CONTEXT_0, &tlv(TAG_SEQUENCE, &ed));
+        tlv(TAG_SEQUENCE, &[tlv(TAG_OID, ID_ENVELOPED_DATA), content].concat())
+    }
+
+    fn check(der: &[u8]) -> Result<(), SmimeError> {
+        check_enveloped_data(der, Deadline::NONE)
+    }
+
+    fn recipient() -> Vec<u8> {
+        tlv(TAG_SEQUENCE, &[tlv(TAG_INTEGER, &[0]), tlv(TAG_OCTET_STRING, &[0x55; 200])].concat())
+    }
+
+    #[test]
+    fn accepts_enveloped_data() {
+        let ciphertext = tlv(TAG_CONTEXT_0_PRIMITIVE, &[0xAA; 300]);
+        assert_eq!(check(&enveloped(0, &recipient(), &ciphertext, &[])), Ok(()));
+        assert_eq!(check(&enveloped(2, &recipient(), &ciphertext, &[])), Ok(()));
+
+        let attrs = tlv(TAG_CONTEXT_1, &tlv(TAG_SEQUENCE, &[]));
+        assert_eq!(check(&enveloped(2, &recipient(), &ciphertext, &attrs)), Ok(()));
+    }
+
+    #[test]
+    fn accepts_streaming_ber() {
+        let segments = [tlv(TAG_OCTET_STRING, &[0xAA; 100]), tlv(TAG_OCTET_STRING, &[0xBB; 10])].concat();
+        let ciphertext = indefinite(TAG_CONTEXT_0, &segments);
+        let algorithm = tlv(TAG_SEQUENCE, &tlv(TAG_OID, AES256_CBC));
+        let eci = indefinite(TAG_SEQUENCE, &[tlv(TAG_OID, ID_DATA), algorithm, ciphertext].concat());
+        let ed = indefinite(TAG_SEQUENCE, &[tlv(TAG_INTEGER, &[0]), tlv(TAG_SET, &recipient()), eci].concat());
+        let der = indefinite(
+            TAG_SEQUENCE,
+            &[tlv(TAG_OID, ID_ENVELOPED_DATA), indefinite(TAG_CONTEXT_0, &ed)].concat(),
+        );
+        assert_eq!(check(&der), Ok(()));
+    }
+
+    #[test]
+    fn rejects_invalid_structures() {
+        let ciphertext = tlv(TAG_CONTEXT_0_PRIMITIVE, &[0xAA; 16]);
+        assert_eq!(
+            check(&enveloped(1, &recipient(), &ciphertext, &[])),
+            Err(SmimeError::UnsupportedVersion(1))
+        );
+        assert_eq!(
+            check(&enveloped(0, &[], &ciphertext, &[])),
+            Err(SmimeError::NoRecipientInfos)
+        );
+        assert_eq!(
+            check(&enveloped(0, &recipient(), &tlv(TAG_CONTEXT_0_PRIMITIVE, &[]), &[])),
+            Err(SmimeError::MissingEncryptedContent)
+        );
+        assert_eq!(
+            check(&enveloped(0, &recipient(), &[], &[])),
+            Err(SmimeError::MissingEncryptedContent)
+        );
+
+        let valid = enveloped(0, &recipient(), &ciphertext, &[]);
+        assert_eq!(check(&[valid.clone(), vec![0]].concat()), Err(SmimeError::TrailingData));
+        for len in 0..valid.len() {
+            assert!(check(&valid[..len]).is_err(), "truncated to {}", len);
+        }
+
+        let signed_data_oid = [0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x07, 0x02];
+        let signed = tlv(TAG_SEQUENCE, &[tlv(TAG_OID, &signed_data_oid), tlv(TAG_CONTEXT_0, &[])].concat());
+        assert_eq!(check(&signed), Err(SmimeError::NotEnvelopedData));
+    }
+
+    #[test]
+    fn limits_indefinite_nesting() {
+        let mut nested = Vec::new();
+        for _ in 0..100 {
+            nested = indefinite(TAG_SEQUENCE, &nested);
+        }
+        assert_eq!(check(&nested), Err(SmimeError::Malformed("nesting too deep")));
+    }
+
+    #[test]
+    fn stops_at_deadline() {
+        let ciphertext = tlv(TAG_CONTEXT_0_PRIMITIVE, &[0xAA; 16]);
+        let der = enveloped(0, &recipient(), &ciphertext, &[]);
+        let expired = Deadline::after(std::time::Duration::ZERO);
+        assert_eq!(check_enveloped_data(&der, expired), Err(SmimeError::Timeout));
+    }
+}
@@ -1,66 +1,158 @@
+use crate::check_pool::{CheckPool, Deadline};
 use crate::config::Config;
-use crate::filter::{check_encrypted, is_securejoin, ENCRYPTION_NEEDED_523};
+use crate::filter::{
+    check_autocrypt_setup, check_bounce, check_encrypted, check_inline_pgp, check_list_wrapped, check_mime_limits,
+    check_originator_headers, check_pkesk_count, check_smime, encryption_needed, has_inline_armor,
+    has_wrapped_encrypted_part, is_pkcs7_mime, is_securejoin, normalize_outgoing_armor, prescan_mime_limits,
+    CHECK_QUEUE_FULL_451, CHECK_QUEUE_TIMEOUT_451, CHECK_TIMEOUT_554, IMPLAUSIBLE_KEYS_554, MIME_TOO_COMPLEX_552,
+    SECUREJOIN_RATE_LIMITED_450, SECUREJOIN_TOO_LARGE_552,
+};
+use crate::metrics::Metrics;
+use crate::proxy_protocol;
 use crate::rate_limit::SendRateLimiter;
-use mail_parser::{Message, MimeHeaders};
+use crate::transport::{Listener, Peer, SocketPermissions, Stream, Upstream};
+use mail_parser::Message;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct SmtpProxy {
     config: Arc<Config>,
     rate_limiter: Arc<SendRateLimiter>,
+    check_pool: Arc<CheckPool>,
+    metrics: Arc<Metrics>,
     mode: String,
 }
 
 impl SmtpProxy {
-    pub fn new(config: Arc<Config>, rate_limiter: Arc<SendRateLimiter>, mode: String) -> Self {
+    pub fn new(
+        config: Arc<Config>,
+        rate_limiter: Arc<SendRateLimiter>,
+        check_pool: Arc<CheckPool>,
+        metrics: Arc<Metrics>,
+        mode: String,
+    ) -> Self {
         Self {
             config,
             rate_limiter,
+            check_pool,
+            metrics,
             mode,
         }
     }
 
     pub async fn run(&self) -> anyhow::Result<()> {
-        let port = if self.mode == "outgoing" {
-            self.config.filtermail_smtp_port
+        let (addrs, socket) = if self.mode == "outgoing" {
+            (&self.config.filtermail_listen, &self.config.filtermail_smtp_socket)
         } else {
-            self.config.filtermail_smtp_port_incoming
+            (&self.config.filtermail_listen_incoming, &self.config.filtermail_smtp_socket_incoming)
         };
 
-        let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
-        eprintln!("Serving {} on 127.0.0.1:{}", self.mode, port);
+        let mut listeners = Vec::new();
+        for addr in addrs {
+            let listener = Listener::bind_tcp(*addr)
+                .map_err(|e| anyhow::anyhow!("Failed to bind {}: {}", addr, e))?;
+            listeners.push(listener);
+            eprintln!("Serving {} on {}", self.mode, addr);
+        }
+        if let Some(path) = socket {
+            let perms = SocketPermissions {
+                owner: self.config.filtermail_socket_owner.clone(),
+                group: self.config.filtermail_socket_group.clone(),
+                mode: self.config.filtermail_socket_mode,
+            };
+            listeners.push(Listener::bind_unix(path, &perms)?);
+            eprintln!("Serving {} on unix:{}", self.mode, path.display());
+        }
+        if listeners.is_empty() {
+            anyhow::bail!("No listen address configured for {}", self.mode);
+        }
 
-        loop {
-            let (stream, _) = listener.accept().await?;
-            let config = Arc::clone(&self.config);
-            let rate_limiter = Arc::clone(&self.rate_limiter);
-            let mode = self.mode.clone();
+        let mut tasks = JoinSet::new();
+        for listener in listeners {
+            tasks.spawn(serve(
+                listener,
+                Arc::clone(&self.config),
+                Arc::clone(&self.rate_limiter),
+                Arc::clone(&self.check_pool),
+                Arc::clone(&self.metrics),
+                self.mode.clone(),
+            ));
+        }
 
-            tokio::spawn(async move {
-                if let Err(e) = handle_connection(stream, config, rate_limiter, mode).await {
-                    eprintln!("Error handling connection: {}", e);
-                }
-            });
+        // Accept loops only return on error, so the first result ends the proxy.
+        match tasks.join_next().await {
+            Some(result) => result?,
+            None => Ok(()),
         }
     }
 }
 
+async fn serve(
+    listener: Listener,
+    config: Arc<Config>,
+    rate_limiter: Arc<SendRateLimiter>,
+    check_pool: Arc<CheckPool>,
+    metrics: Arc<Metrics>,
+    mode: String,
+) -> anyhow::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let config = Arc::clone(&config);
+        let rate_limiter = Arc::clone(&rate_limiter);
+        let check_pool = Arc::clone(&check_pool);
+        let metrics = Arc::clone(&metrics);
+        let mode = mode.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, peer, config, rate_limiter, check_pool, metrics, mode).await {
+                eprintln!("Error handling connection: {}", e);
+            }
+        });
+    }
+}
+
 async fn handle_connection(
-    mut stream: TcpStream,
+    stream: Box<dyn Stream>,
+    mut peer_addr: Peer,
     config: Arc<Config>,
     rate_limiter: Arc<SendRateLimiter>,
+    check_pool: Arc<CheckPool>,
+    metrics: Arc<Metrics>,
     mode: String,
 ) -> anyhow::Result<()> {
-    let peer_addr = stream.peer_addr()?;
-    let (reader, mut writer) = stream.split();
+    let counters = metrics.for_mode(&mode);
+    counters.connections.fetch_add(1, Ordering::Relaxed);
+    let (reader, mut writer) = tokio::io::split(stream);
     let mut reader = BufReader::new(reader);
     let mut line = String::new();
+    // Only set when the client address comes from a PROXY header, as direct peers are the local Postfix.
+    let mut client_ip = None;
+
+    // Behind a trusted load balancer the real client is only known from the PROXY header.
+    if let Peer::Tcp(proxy_addr) = peer_addr
+        && config.expects_proxy_header(proxy_addr.ip())
+    {
+        let header = tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy_protocol::read_header(&mut reader))
+            .await
+            .map_err(|_| anyhow::anyhow!("Timeout reading PROXY header from {}", proxy_addr))?
+            .map_err(|e| anyhow::anyhow!("Invalid PROXY header from {}: {}", proxy_addr, e))?;
+        if let Some(client_addr) = header {
+            peer_addr = Peer::Tcp(client_addr);
+            client_ip = Some(client_addr.ip());
+        }
+    }
 
     writer.write_all(b"220 localhost ESMTP\r\n").await?;
     eprintln!("SMTP: {} New connection from {}", mode, peer_addr);
 
     let mut mail_from = String::new();
+    // Whether the current transaction has an accepted MAIL FROM.
+    let mut has_mail_from = false;
     let mut rcpt_tos = Vec::new();
 
     loop {
@@ -78,22 +170,53 @@ async fn handle_connection(
         } else if cmd_upper.starts_with("MAIL FROM:") {
             let addr = extract_addr(cmd, "MAIL FROM:");
             mail_from = addr.clone();
-            
-            if mode == "outgoing" {
-                if !rate_limiter.is_sending_allowed(&mail_from, config.max_user_send_per_minute) {
-                    eprintln!("SMTP: Rate limit exceeded for {}", mail_from);
-                    writer.write_all(format!("450 4.7.1: Too much mail from {}\r\n", mail_from).as_bytes()).await?;
-                    continue;
-                }
+            has_mail_from = false;
+            rcpt_tos.clear();
+
+            if mode == "outgoing" && !config.is_outgoing_sender_allowed(&mail_from) {
+                eprintln!("REJECT: Outgoing sender <{}> not on mail domain (client {})", mail_from, peer_addr);
+                writer.write_all(format!("553 5.7.1 Sender address <{}> not allowed\r\n", mail_from).as_bytes()).await?;
+                mail_from.clear();
+                continue;
+            }
+
+            if let Some(ip) = client_ip
+                && config.max_client_send_per_minute > 0
+                && !rate_limiter.is_client_allowed(ip, config.max_client_send_per_minute)
+            {
+                eprintln!("SMTP: Rate limit exceeded for client {}", ip);
+                writer.write_all(format!("450 4.7.1: Too much mail from {}\r\n", ip).as_bytes()).await?;
+                mail_from.clear();
+                continue;
+            }
+
+            if mode == "outgoing" && !rate_limiter.is_sending_allowed(&mail_from, config.max_user_send_per_minute) {
+                eprintln!("SMTP: Rate limit exceeded for {} (client {})", mail_from, peer_addr);
+                writer.write_all(format!("450 4.7.1: Too much mail from {}\r\n", mail_from).as_bytes()).await?;
+                mail_from.clear();
+                continue;
             }
             eprintln!("SMTP: MAIL FROM:<{}>", mail_from);
+            has_mail_from = true;
             writer.write_all(b"250 OK\r\n").await?;
         } else if cmd_upper.starts_with("RCPT TO:") {
+            if !has_mail_from {
+                writer.write_all(b"503 5.5.1 Error: need MAIL command\r\n").await?;
+                continue;
+            }
             let addr = extract_addr(cmd, "RCPT TO:");
             eprintln!("SMTP: RCPT TO:<{}>", addr);
             rcpt_tos.push(addr);
             writer.write_all(b"250 OK\r\n").await?;
         } else if cmd_upper == "DATA" {
+            if !has_mail_from {
+                writer.write_all(b"503 5.5.1 Error: need MAIL command\r\n").await?;
+                continue;
+            }
+            if rcpt_tos.is_empty() {
+                writer.write_all(b"503 5.5.1 Error: need RCPT command\r\n").await?;
+                continue;
+            }
             writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n").await?;
             
             let mut data = Vec::new();
@@ -113,40 +236,78 @@ async fn handle_connection(
                 data.extend_from_slice(content.as_bytes());
             }
 
-            // Processing DATA
-            let msg = mail_parser::MessageParser::default().parse(&data).ok_or_else(|| anyhow::anyhow!("Failed to parse message"))?;
-            let error = check_data(&msg, &mail_from, &rcpt_tos, &config, &mode);
-            
-            if let Some(err_msg) = error {
-                eprintln!("SMTP: Rejecting data: {}", err_msg);
-                writer.write_all(format!("{}\r\n", err_msg).as_bytes()).await?;
-            } else {
-                // Re-inject
-                let reinject_port = if mode == "outgoing" {
-                    config.postfix_reinject_port
-                } else {
-                    config.postfix_reinject_port_incoming
-                };
-                
-                eprintln!("SMTP: Re-injecting {} bytes to port {}", data.len(), reinject_port);
-                match reinject(&mail_from, &rcpt_tos, &data, reinject_port).await {
-                    Ok(_) => {
-                        writer.write_all(b"250 OK\r\n").await?;
+            // Parsing and checking are CPU-bound, so they run on the bounded check pool under a time budget.
+            // Waiting for a slot is bounded separately, so a busy pool defers messages instead of failing them.
+            let budget = Duration::from_secs(config.message_check_timeout);
+            let check = check_pool.spawn({
+                let config = Arc::clone(&config);
+                let rate_limiter = Arc::clone(&rate_limiter);
+                let (peer_addr, mail_from, rcpt_tos, mode) =
+                    (peer_addr.clone(), mail_from.clone(), rcpt_tos.clone(), mode.clone());
+                move || process_data(data, &peer_addr, &mail_from, &rcpt_tos, &config, &rate_limiter, &mode)
+            });
+            let result = match tokio::time::timeout(budget, check).await {
+                Ok(Some(handle)) => match tokio::time::timeout(budget, handle).await {
+                    Ok(joined) => joined??,
+                    Err(_) => {
+                        eprintln!(
+                            "REJECT: Checking message from {} took more than {}s",
+                            mail_from, config.message_check_timeout
+                        );
+                        Err(CHECK_TIMEOUT_554.to_string())
                     }
-                    Err(e) => {
-                        eprintln!("SMTP: Re-inject failed: {}", e);
-                        writer.write_all(b"451 Error re-injecting mail\r\n").await?;
+                },
+                Ok(None) => {
+                    eprintln!("REJECT: Too many messages being checked, deferring message from {}", mail_from);
+                    Err(CHECK_QUEUE_FULL_451.to_string())
+                }
+                Err(_) => {
+                    eprintln!(
+                        "REJECT: No free check slot for message from {} within {}s, deferring it",
+                        mail_from, config.message_check_timeout
+                    );
+                    Err(CHECK_QUEUE_TIMEOUT_451.to_string())
+                }
+            };
+
+            match result {
+                Err(err_msg) => {
+                    eprintln!("SMTP: Rejecting data from {}: {}", peer_addr, err_msg.replace("\r\n", " "));
+                    counters.rejected.fetch_add(1, Ordering::Relaxed);
+                    writer.write_all(format!("{}\r\n", err_msg).as_bytes()).await?;
+                }
+                Ok((data, accepted)) => {
+                    // Re-inject
+                    let upstream = reinject_upstream(&config, &mode);
+
+                    eprintln!("SMTP: Re-injecting {} bytes to {}", data.len(), upstream);
+                    match reinject(&mail_from, &rcpt_tos, &data, &upstream).await {
+                        Ok(_) => {
+                            // Counted only now, so a request deferred by the upstream and retried counts once.
+                            if accepted == Accepted::SecureJoinRequest {
+                                rate_limiter.record_securejoin(&mail_from, &rcpt_tos);
+                            }
+                            counters.accepted.fetch_add(1, Ordering::Relaxed);
+                            writer.write_all(b"250 OK\r\n").await?;
+                        }
+                        Err(e) => {
+                            eprintln!("SMTP: Re-inject failed: {}", e);
+                            counters.reinject_failed.fetch_add(1, Ordering::Relaxed);
+                            writer.write_all(b"451 Error re-injecting mail\r\n").await?;
+                        }
                     }
                 }
             }
             // Reset for next message if needed (though usually one per connection in simple cases)
             mail_from.clear();
+            has_mail_from = false;
             rcpt_tos.clear();
         } else if cmd_upper == "QUIT" {
             writer.write_all(b"221 Bye\r\n").await?;
             break;
         } else if cmd_upper == "RSET" {
             mail_from.clear();
+            has_mail_from = false;
             rcpt_tos.clear();
             writer.write_all(b"250 OK\r\n").await?;
         } else if cmd_upper == "NOOP" {
@@ -159,6 +320,18 @@ async fn handle_connection(
     Ok(())
 }
 
+fn reinject_upstream(config: &Config, mode: &str) -> Upstream {
+    let (port, socket) = if mode == "outgoing" {
+        (config.postfix_reinject_port, &config.postfix_reinject_socket)
+    } else {
+        (config.postfix_reinject_port_incoming, &config.postfix_reinject_socket_incoming)
+    };
+    match socket {
+        Some(path) => Upstream::Unix(path.clone()),
+        None => Upstream::Tcp(format!("127.0.0.1:{}", port)),
+    }
+}
+
 fn extract_addr(cmd: &str, prefix: &str) -> String {
     let mut addr = cmd[prefix.len()..].trim();
     // Remove options like SMTPUTF8 or SIZE first
@@ -171,40 +344,158 @@ fn extract_addr(cmd: &str, prefix: &str) -> String {
     addr.to_string()
 }
 
+/// Parses a message, applies the MIME limits, optionally normalizes its armor
+/// and runs [`check_data`]. Returns the message to re-inject, or the reply to
+/// reject it with.
+fn process_data(
+    mut data: Vec<u8>,
+    peer: &Peer,
+    mail_from: &str,
+    rcpt_tos: &[String],
+    config: &Config,
+    rate_limiter: &SendRateLimiter,
+    mode: &str,
+) -> anyhow::Result<Result<(Vec<u8>, Accepted), String>> {
+    // Checks stop on their own once the budget is spent, so a crafted message cannot keep a slot busy.
+    let deadline = Deadline::after(Duration::from_secs(config.message_check_timeout));
+    if let Err(reason) = prescan_mime_limits(&data, &config.mime_limits) {
+        eprintln!("REJECT: Message from {} exceeds MIME limits: {}", mail_from, reason);
+        return Ok(Err(MIME_TOO_COMPLEX_552.to_string()));
+    }
+    let parser = mail_parser::MessageParser::default();
+    let mut msg = parser.parse(&data).ok_or_else(|| anyhow::anyhow!("Failed to parse message"))?;
+    if let Err(reason) = check_mime_limits(&msg, &config.mime_limits, deadline) {
+        eprintln!("REJECT: Message from {} exceeds MIME limits: {}", mail_from, reason);
+        return Ok(Err(MIME_TOO_COMPLEX_552.to_string()));
+    }
+
+    if mode == "outgoing"
+        && config.normalize_outgoing_armor
+        && let Some(rewrite) = normalize_outgoing_armor(&msg, &config.openpgp_policy, deadline)
+    {
+        drop(msg);
+        eprintln!("SMTP: Normalized armor of outgoing message from {}", mail_from);
+        data.splice(rewrite.range, rewrite.armor.into_bytes());
+        msg = parser.parse(&data).ok_or_else(|| anyhow::anyhow!("Failed to parse message"))?;
+    }
+
+    let verdict = check_data(&msg, peer, mail_from, rcpt_tos, config, rate_limiter, mode, deadline);
+    drop(msg);
+    // A check cut short by the deadline has no meaningful verdict.
+    if deadline.is_expired() {
+        eprintln!("REJECT: Checking message from {} took more than {}s", mail_from, config.message_check_timeout);
+        return Ok(Err(CHECK_TIMEOUT_554.to_string()));
+    }
+    Ok(verdict.map(|accepted| (data, accepted)))
+}
+
+/// What kind of message passed the checks, for limits applied once it was delivered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Accepted {
+    Message,
+    /// An unencrypted Secure-Join request, counted against the sender's quota.
+    SecureJoinRequest,
+}
+
+#[allow(clippy::too_many_arguments)]
 fn check_data(
     msg: &Message,
+    peer: &Peer,
     mail_from: &str,
     rcpt_tos: &[String],
     config: &Config,
+    rate_limiter: &SendRateLimiter,
     mode: &str,
-) -> Option<String> {
+    deadline: Deadline,
+) -> Result<Accepted, String> {
     let outgoing = mode == "outgoing";
-    let is_encrypted = check_encrypted(msg, outgoing);
-    let is_sj = is_securejoin(msg);
+    // `Ok(None)` marks an accepted S/MIME message, which has no OpenPGP session keys.
+    let mut encrypted = check_encrypted(msg, outgoing, &config.openpgp_policy, deadline).map(Some);
+    if encrypted.is_err() && config.accepts_smime(outgoing) && is_pkcs7_mime(msg) {
+        encrypted = check_smime(msg, deadline).map(|()| None);
+    }
+    // The fallbacks only replace the reason for refusing the message when their shape matches.
+    if encrypted.is_err() && !outgoing && config.inline_pgp_incoming && has_inline_armor(msg) {
+        encrypted = check_inline_pgp(msg, outgoing, &config.openpgp_policy, deadline).map(Some);
+    }
+    if encrypted.is_err() && !outgoing && config.list_footer_incoming && has_wrapped_encrypted_part(msg) {
+        encrypted = check_list_wrapped(msg, outgoing, &config.openpgp_policy, config.list_footer_max_size, deadline).map(Some);
+        if encrypted.is_ok() {
+            eprintln!("SMTP: Encrypted mail wrapped by mailing list (Subject: {:?})", msg.subject());
+        }
+    }
+    let is_encrypted = encrypted.is_ok();
+    // Why the message does not count as encrypted, reported to the client if it is refused.
+    let reason = encrypted.as_ref().err().map_or("", |e| e.as_str());
+    let is_sj = is_securejoin(msg, deadline);
 
     if outgoing {
-        let from_header = msg.from().and_then(|f| f.first()?.address.as_ref());
-        if let Some(from_addr) = from_header {
-            if mail_from.to_lowercase() != from_addr.to_lowercase() {
-                return Some(format!("500 Invalid FROM <{}> for <{}>", from_addr, mail_from));
+        // System senders such as the null sender have no mailbox to match the headers against.
+        if !config.is_allowlisted_sender(mail_from)
+            && let Err(reply) = check_originator_headers(msg, mail_from, &config.sender_domains)
+        {
+            eprintln!(
+                "REJECT: Outgoing mail with invalid originator headers from {} (client {}): {}",
+                mail_from, peer, reply
+            );
+            return Err(reply);
+        }
+
+        if let Ok(None) = &encrypted {
+            eprintln!("SMTP: S/MIME enveloped-data");
+        }
+        if let Ok(Some(summary)) = &encrypted
+            && config.pkesk_check_outgoing
+            && let Err(reason) = check_pkesk_count(summary, mail_from, rcpt_tos, config.pkesk_max_extra)
+        {
+            // Key IDs reveal who a user corresponds with, so they are only logged for rejected mail.
+            let keys: Vec<String> = summary.pkesk_recipients.iter().map(|k| k.to_string()).collect();
+            eprintln!(
+                "REJECT: Outgoing encrypted mail with implausible keys: {} (keys [{}])",
+                reason,
+                keys.join(", ")
+            );
+            return Err(IMPLAUSIBLE_KEYS_554.to_string());
+        }
+
+        if is_encrypted {
+            return Ok(Accepted::Message);
+        }
+
+        // Secure-Join requests are the only cleartext any user may send, so they get their own limits.
+        if is_sj {
+            if msg.raw_message().len() > config.securejoin_max_message_size {
+                eprintln!("REJECT: Secure-Join message of {} bytes from {}", msg.raw_message().len(), mail_from);
+                return Err(SECUREJOIN_TOO_LARGE_552.to_string());
+            }
+            if !rate_limiter.is_securejoin_allowed(
+                mail_from,
+                rcpt_tos,
+                config.securejoin_max_per_hour,
+                config.securejoin_max_recipients_per_hour,
+            ) {
+                eprintln!("REJECT: Secure-Join rate limit exceeded for {}", mail_from);
+                return Err(SECUREJOIN_RATE_LIMITED_450.to_string());
             }
+            return Ok(Accepted::SecureJoinRequest);
         }
 
-        if is_encrypted || is_sj {
-            return None;
+        if config.is_role_mailbox(mail_from) {
+            return Ok(Accepted::Message);
         }
 
         if config.passthrough_senders.iter().any(|s| s == mail_from) {
-            return None;
+            return Ok(Accepted::Message);
         }
 
         // Allow self-sent Autocrypt Setup Message
-        if rcpt_tos.len() == 1 && rcpt_tos[0].to_lowercase() == mail_from.to_lowercase() {
-            if msg.subject() == Some("Autocrypt Setup Message") {
-                // In Python: if message.get_content_type() == "multipart/mixed": return
-                if msg.is_content_type("multipart", "mixed") {
-                    return None;
-                }
+        if rcpt_tos.len() == 1
+            && rcpt_tos[0].to_lowercase() == mail_from.to_lowercase()
+            && msg.header("Autocrypt-Setup-Message").is_some()
+        {
+            match check_autocrypt_setup(msg, &config.openpgp_policy, deadline) {
+                Ok(()) => return Ok(Accepted::Message),
+                Err(reason) => eprintln!("REJECT: Invalid Autocrypt Setup Message: {}", reason),
             }
         }
 
@@ -212,37 +503,48 @@ fn check_data(
             if recipient_matches_passthrough(rcpt, &config.passthrough_recipients) {
                 continue;
             }
-            eprintln!("REJECT: Outgoing unencrypted mail rejected (Subject: {:?})", msg.subject());
-            return Some(ENCRYPTION_NEEDED_523.to_string());
+            eprintln!(
+                "REJECT: Outgoing unencrypted mail from client {} rejected (Subject: {:?}): {}",
+                peer,
+                msg.subject(),
+                reason
+            );
+            return Err(encryption_needed(reason));
         }
     } else {
         // Incoming
         if is_encrypted || is_sj {
-            return None;
+            return Ok(Accepted::Message);
         }
 
         // Mailer-daemon
         let auto_submitted = msg.header("Auto-Submitted").and_then(|h| h.as_text());
         if auto_submitted.is_some() {
             let from_header = msg.from().and_then(|f| f.first()?.address.as_ref());
-            if let Some(from_addr) = from_header {
-                if from_addr.to_lowercase().starts_with("mailer-daemon@") {
-                    if msg.is_content_type("multipart", "report") {
-                        return None;
-                    }
+            if let Some(from_addr) = from_header
+                && from_addr.to_lowercase().starts_with("mailer-daemon@")
+            {
+                match check_bounce(msg, mail_from, config.bounce_max_text_size, config.bounce_max_returned_size) {
+                    Ok(()) => return Ok(Accepted::Message),
+                    Err(reason) => eprintln!("REJECT: Invalid bounce from {}: {}", from_addr, reason),
                 }
             }
         }
 
         for rcpt in rcpt_tos {
             if !config.is_incoming_cleartext_ok(rcpt) {
-                eprintln!("REJECT: Incoming unencrypted mail rejected for {}", rcpt);
-                return Some(ENCRYPTION_NEEDED_523.to_string());
+                eprintln!(
+                    "REJECT: Incoming unencrypted mail from client {} rejected for {}: {}",
+                    peer,
+                    rcpt,
+                    reason
+                );
+                return Err(encryption_needed(reason));
             }
         }
     }
 
-    None
+    Ok(Accepted::Message)
 }
 
 fn recipient_matches_passthrough(recipient: &str, passthrough_recipients: &[String]) -> bool {
@@ -261,16 +563,16 @@ async fn reinject(
     mail_from: &str,
     rcpt_tos: &[String],
     data: &[u8],
-    port: u16,
+    upstream: &Upstream,
 ) -> anyhow::Result<()> {
-    let mut stream = TcpStream::connect(format!("127.0.0.1:{}", port)).await?;
-    let (reader, mut writer) = stream.split();
+    let stream = upstream.connect().await?;
+    let (reader, mut writer) = tokio::io::split(stream);
     let mut reader = BufReader::new(reader);
     let mut line = String::new();
 
     reader.read_line(&mut line).await?; // 220
 
-    writer.write_all(format!("HELO localhost\r\n").as_bytes()).await?;
+    writer.write_all(b"HELO localhost\r\n").await?;
     line.clear();
     reader.read_line(&mut line).await?;
 
@@ -307,3 +609,64 @@ async fn reinject(
     writer.write_all(b"QUIT\r\n").await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+
+    /// Sends each command in turn to an outgoing session and returns the reply codes.
+    async fn reply_codes(commands: &[&str]) -> Vec<String> {
+        let config = Arc::new(Config::for_tests(""));
+        let check_pool = Arc::new(CheckPool::new(1, 1));
+        let (client, server) = tokio::io::duplex(4096);
+        let session = tokio::spawn(handle_connection(
+            Box::new(server),
+            Peer::Unix,
+            config,
+            Arc::new(SendRateLimiter::new()),
+            check_pool,
+            Arc::new(Metrics::default()),
+            "outgoing".to_string(),
+        ));
+
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with("220 "));
+        let mut codes = Vec::new();
+        for command in commands {
+            writer.write_all(format!("{command}\r\n").as_bytes()).await.unwrap();
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            codes.push(line[..3].to_string());
+        }
+        writer.write_all(b"QUIT\r\n").await.unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "221 Bye\r\n");
+        session.await.unwrap().unwrap();
+        codes
+    }
+
+    #[tokio::test]
+    async fn transaction_order() {
+        assert_eq!(reply_codes(&["RCPT TO:<b@example.org>", "DATA"]).await, ["503", "503"]);
+        assert_eq!(
+            reply_codes(&["MAIL FROM:<a@example.org>", "DATA", "RCPT TO:<b@example.org>"]).await,
+            ["250", "503", "250"]
+        );
+        // A refused sender leaves no transaction to add recipients to.
+        assert_eq!(
+            reply_codes(&["MAIL FROM:<a@example.com>", "RCPT TO:<b@example.org>", "DATA"]).await,
+            ["553", "503", "503"]
+        );
+        assert_eq!(
+            reply_codes(&["MAIL FROM:<a@example.org>", "RCPT TO:<b@example.org>", "RSET", "RCPT TO:<b@example.org>"])
+                .await,
+            ["250", "250", "250", "503"]
+        );
+        assert_eq!(reply_codes(&["MAIL FROM:<>", "RCPT TO:<b@example.org>"]).await, ["250", "250"]);
+    }
+}
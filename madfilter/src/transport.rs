@@ -0,0 +1,275 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use nix::unistd::{Group, User};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+/// A bidirectional byte stream, either TCP or a Unix domain socket.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Address of the remote end of an accepted connection.
+#[derive(Debug, Clone)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    Unix,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix => write!(f, "unix socket"),
+        }
+    }
+}
+
+/// A TCP address to listen on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenAddr {
+    Addr(SocketAddr),
+    /// `[::]` with `IPV6_V6ONLY` cleared, accepting both IPv4 and IPv6.
+    DualStack(u16),
+}
+
+impl ListenAddr {
+    /// Parses one entry of a listen address list.
+    ///
+    /// Accepted forms are `*` (dual-stack wildcard), a bare IPv4 or IPv6
+    /// address, or an address with port such as `10.0.0.1:10080` or
+    /// `[fd00::1]:10080`. `default_port` is used when no port is given.
+    pub fn parse(s: &str, default_port: u16) -> anyhow::Result<Self> {
+        if s == "*" {
+            return Ok(ListenAddr::DualStack(default_port));
+        }
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(ListenAddr::Addr(addr));
+        }
+        let ip = s.strip_prefix('[').and_then(|s| s.strip_suffix(']')).unwrap_or(s);
+        let ip: IpAddr = ip.parse().map_err(|_| anyhow::anyhow!("Invalid listen address: {}", s))?;
+        Ok(ListenAddr::Addr(SocketAddr::new(ip, default_port)))
+    }
+
+    /// Returns the IPv4-only equivalent used when IPv6 is disabled.
+    pub fn without_ipv6(self) -> Option<Self> {
+        match self {
+            ListenAddr::DualStack(port) => Some(ListenAddr::Addr(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port))),
+            ListenAddr::Addr(addr) if addr.is_ipv6() => None,
+            addr => Some(addr),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Addr(addr) => write!(f, "{}", addr),
+            ListenAddr::DualStack(port) => write!(f, "[::]:{} (dual-stack)", port),
+        }
+    }
+}
+
+/// Ownership and permission bits applied to a listening Unix socket.
+#[derive(Debug, Clone)]
+pub struct SocketPermissions {
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub mode: u32,
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn bind_tcp(addr: ListenAddr) -> anyhow::Result<Self> {
+        let (addr, only_v6) = match addr {
+            ListenAddr::Addr(addr) => (addr, addr.is_ipv6()),
+            ListenAddr::DualStack(port) => (SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port), false),
+        };
+
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(only_v6)?;
+        }
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+
+        Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+    }
+
+    pub fn bind_unix(path: &Path, perms: &SocketPermissions) -> anyhow::Result<Self> {
+        // Only a stale socket left behind by a previous run may be replaced.
+        if let Ok(meta) = fs::symlink_metadata(path)
+            && !meta.file_type().is_socket()
+        {
+            anyhow::bail!("{} exists and is not a socket", path.display());
+        }
+
+        // Bind inside a private directory and move the socket into place once its owner
+        // and mode are final, so it is never reachable with the umask permissions.
+        let name = path.file_name().ok_or_else(|| anyhow::anyhow!("Invalid socket path: {}", path.display()))?;
+        let mut private_name = std::ffi::OsString::from(".");
+        private_name.push(name);
+        private_name.push(format!(".{}", std::process::id()));
+        let private_dir = path.with_file_name(private_name);
+        let _ = fs::remove_dir_all(&private_dir);
+        fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+
+        let private_path = private_dir.join(name);
+        let result = bind_private_unix(&private_path, path, perms);
+        let _ = fs::remove_dir_all(&private_dir);
+        Ok(Listener::Unix(result?))
+    }
+
+    pub async fn accept(&self) -> io::Result<(Box<dyn Stream>, Peer)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), Peer::Tcp(addr)))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), Peer::Unix))
+            }
+        }
+    }
+}
+
+/// Where accepted mail is handed back to Postfix.
+#[derive(Debug, Clone)]
+pub enum Upstream {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Upstream {
+    pub async fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        match self {
+            Upstream::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr).await?)),
+            Upstream::Unix(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::Tcp(addr) => write!(f, "{}", addr),
+            Upstream::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Binds a socket at `private_path`, applies `perms` and renames it to `path`.
+fn bind_private_unix(private_path: &Path, path: &Path, perms: &SocketPermissions) -> anyhow::Result<UnixListener> {
+    let listener = UnixListener::bind(private_path)?;
+
+    let uid = perms.owner.as_deref().map(lookup_uid).transpose()?;
+    let gid = perms.group.as_deref().map(lookup_gid).transpose()?;
+    if uid.is_some() || gid.is_some() {
+        std::os::unix::fs::chown(private_path, uid, gid)?;
+    }
+    fs::set_permissions(private_path, fs::Permissions::from_mode(perms.mode))?;
+    fs::rename(private_path, path)?;
+
+    Ok(listener)
+}
+
+/// Resolves a user name to its uid through NSS, so users from LDAP or sssd work too.
+/// Numeric values are accepted as-is.
+fn lookup_uid(name: &str) -> anyhow::Result<u32> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(id);
+    }
+    let user = User::from_name(name).map_err(|e| anyhow::anyhow!("Looking up user {}: {}", name, e))?;
+    Ok(user.ok_or_else(|| anyhow::anyhow!("User {} not found", name))?.uid.as_raw())
+}
+
+/// Resolves a group name to its gid through NSS. Numeric values are accepted as-is.
+fn lookup_gid(name: &str) -> anyhow::Result<u32> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(id);
+    }
+    let group = Group::from_name(name).map_err(|e| anyhow::anyhow!("Looking up group {}: {}", name, e))?;
+    Ok(group.ok_or_else(|| anyhow::anyhow!("Group {} not found", name))?.gid.as_raw())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_listen_addr() {
+        let parse = |s: &str| ListenAddr::parse(s, 10080).map(|addr| addr.to_string()).map_err(|e| e.to_string());
+        assert_eq!(parse("*").unwrap(), "[::]:10080 (dual-stack)");
+        assert_eq!(parse("127.0.0.1").unwrap(), "127.0.0.1:10080");
+        assert_eq!(parse("10.0.0.1:2525").unwrap(), "10.0.0.1:2525");
+        assert_eq!(parse("::1").unwrap(), "[::1]:10080");
+        assert_eq!(parse("[fd00::1]").unwrap(), "[fd00::1]:10080");
+        assert_eq!(parse("[fd00::1]:2525").unwrap(), "[fd00::1]:2525");
+        for invalid in ["", "localhost", "[fd00::1", "fd00::1]", "[127.0.0.1]:x", "10.0.0.1:", "**"] {
+            assert_eq!(parse(invalid), Err(format!("Invalid listen address: {}", invalid)), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn listen_addr_without_ipv6() {
+        let without_ipv6 = |s: &str| ListenAddr::parse(s, 10080).unwrap().without_ipv6().map(|addr| addr.to_string());
+        assert_eq!(without_ipv6("*").as_deref(), Some("0.0.0.0:10080"));
+        assert_eq!(without_ipv6("127.0.0.1").as_deref(), Some("127.0.0.1:10080"));
+        assert_eq!(without_ipv6("[::1]:2525"), None);
+        assert_eq!(without_ipv6("::"), None);
+    }
+
+    fn perms(mode: u32) -> SocketPermissions {
+        SocketPermissions { owner: None, group: None, mode }
+    }
+
+    #[tokio::test]
+    async fn unix_socket_gets_configured_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("filtermail.sock");
+
+        for mode in [0o640, 0o600, 0o666] {
+            let listener = Listener::bind_unix(&path, &perms(mode)).unwrap();
+            let meta = fs::symlink_metadata(&path).unwrap();
+            assert!(meta.file_type().is_socket());
+            assert_eq!(meta.permissions().mode() & 0o777, mode);
+            UnixStream::connect(&path).await.unwrap();
+            // Binding again replaces the stale socket.
+            drop(listener);
+        }
+        // Only the socket is left, not the private directory it was bound in.
+        let entries: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+        assert_eq!(entries, ["filtermail.sock"]);
+    }
+
+    #[tokio::test]
+    async fn unix_socket_does_not_replace_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("filtermail.sock");
+        fs::write(&path, "keep").unwrap();
+        assert!(Listener::bind_unix(&path, &perms(0o640)).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep");
+    }
+
+    #[test]
+    fn owner_and_group_lookup() {
+        assert_eq!(lookup_uid("root").unwrap(), 0);
+        assert_eq!(lookup_gid("root").unwrap(), 0);
+        assert_eq!(lookup_uid("1234").unwrap(), 1234);
+        assert_eq!(lookup_gid("1234").unwrap(), 1234);
+        assert!(lookup_uid("no-such-user-madfilter").is_err());
+        assert!(lookup_gid("no-such-group-madfilter").is_err());
+    }
+}